@@ -0,0 +1,475 @@
+extern crate base64;
+extern crate redis;
+extern crate serde_json;
+
+use std::sync::Arc;
+use control::Control;
+use dispatcher::{self, Dispatcher, RedisKeys};
+use health::{Health, Report};
+use http::{self, Request, Response};
+use log;
+use metrics::Metrics;
+use payload::{self, Redactor};
+use registry;
+use redis::{Commands, PipelineCommands};
+use self::serde_json::Value;
+
+/// Tasks listed per page unless `count` says otherwise.
+const PAGE_SIZE: usize = 50;
+
+/// Largest page a client can ask for.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Endpoints served on `--metrics-addr`, and the task API on `--admin-addr`.
+pub struct Admin {
+    keys: RedisKeys,
+    redis_client: redis::Client,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    control: Arc<Control>,
+    redactor: Redactor,
+    consumer: bool,
+    api: bool,
+}
+
+impl Admin {
+    pub fn new(dispatcher: &Dispatcher, redis_client: redis::Client) -> Admin {
+        Admin {
+            keys: RedisKeys::for_channel(&dispatcher.config.db_channel),
+            redis_client,
+            metrics: dispatcher.metrics.clone(),
+            health: dispatcher.health.clone(),
+            control: dispatcher.control.clone(),
+            redactor: dispatcher.config.redactor.clone(),
+            consumer: dispatcher.config.consumer,
+            api: false,
+        }
+    }
+
+    /// Also serves the `/api` endpoints, which can change the queue.
+    pub fn with_api(mut self) -> Admin {
+        self.api = true;
+        self
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let segments: Vec<String> = request.path.trim_matches('/')
+            .split('/')
+            .map(http::percent_decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["metrics"]) => return self.metrics(),
+            ("GET", ["healthz"]) => return report(self.health.liveness()),
+            ("GET", ["readyz"]) => return report(self.health.readiness(self.ping_redis())),
+            (_, ["api", ..]) if !self.api => return Response::not_found(),
+            ("GET", ["api", "tasks"]) => self.list_tasks(request),
+            ("GET", ["api", "tasks", id]) => self.find(id, |key| self.show_task(key)),
+            ("POST", ["api", "tasks", id, "requeue"]) => self.find(id, |key| self.requeue(key)),
+            ("POST", ["api", "tasks", id, "cancel"]) => self.find(id, |key|
+                self.transition(key, &["pending", "delayed"], "dead", |pipe, keys, key| {
+                    let result = json!({"cancelled": true, "finished_at": dispatcher::unix_time()});
+                    pipe.srem(&keys.pending_set, key).ignore()
+                        .zrem(&keys.delayed, key).ignore()
+                        .zrem(&keys.enqueued_at, key).ignore()
+                        .zrem(&keys.arrival, key).ignore()
+                        .hdel(&keys.attempts, key).ignore()
+                        .zadd(&keys.dead, key, dispatcher::unix_time()).ignore()
+                        .hset(&keys.results, key, result.to_string()).ignore();
+                })),
+            ("DELETE", ["api", "tasks", id]) => self.find(id, |key|
+                self.transition(key, &["pending", "delayed", "done", "dead"], "deleted", |pipe, keys, key| {
+                    pipe.srem(&keys.pending_set, key).ignore()
+                        .zrem(&keys.delayed, key).ignore()
+                        .zrem(&keys.enqueued_at, key).ignore()
+                        .zrem(&keys.arrival, key).ignore()
+                        .hdel(&keys.attempts, key).ignore()
+                        .zrem(&keys.done, key).ignore()
+                        .zrem(&keys.dead, key).ignore()
+                        .hdel(&keys.results, key).ignore();
+                })),
+            ("GET", ["api", "consumer"]) => self.show_consumer(),
+            ("GET", ["api", "consumers"]) => self.list_consumers(),
+            ("POST", ["api", "pause"]) => self.set_paused(true),
+            ("POST", ["api", "resume"]) => self.set_paused(false),
+            ("POST", ["api", "workers"]) => Ok(self.resize(request)),
+            (_, ["api", ..]) => Ok(error(404, "not found")),
+            _ => return Response::not_found(),
+        };
+
+        response.unwrap_or_else(|redis_error| {
+            log::error("admin request failed")
+                .field("path", request.path.as_str())
+                .field("error", format!("{:?}", redis_error))
+                .emit();
+            error(500, "failed to reach Redis")
+        })
+    }
+
+    fn ping_redis(&self) -> Result<(), String> {
+        self.redis_client.get_connection()
+            .and_then(|redis_conn| redis::cmd("PING").query::<String>(&redis_conn))
+            .map(|_| ())
+            .map_err(|error| format!("{:?}", error))
+    }
+
+    fn metrics(&self) -> Response {
+        // leave the set sizes out rather than failing the scrape
+        let mut set_sizes = Vec::new();
+        let mut oldest_pending_age = None;
+        match self.redis_client.get_connection() {
+            Ok(redis_conn) => {
+                for &state in &["pending", "processing", "delayed", "done", "dead"] {
+                    let size = match state {
+                        "delayed" | "done" | "dead" => redis_conn.zcard(self.set_of(state).unwrap()),
+                        _ => redis_conn.scard(self.set_of(state).unwrap()),
+                    };
+                    if let Ok(size) = size {
+                        set_sizes.push((state, size));
+                    }
+                }
+                oldest_pending_age = dispatcher::oldest_pending(&redis_conn, &self.keys).ok()
+                    .map(|oldest| oldest.map_or(0, |enqueued_at| dispatcher::unix_time().saturating_sub(enqueued_at)));
+            },
+            Err(error) => {
+                log::error("failed to read set sizes from Redis")
+                    .field("error", format!("{:?}", error))
+                    .emit();
+            }
+        }
+
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: self.metrics.render(&set_sizes, oldest_pending_age),
+        }
+    }
+
+    fn set_of(&self, state: &str) -> Option<&str> {
+        match state {
+            "pending" => Some(&self.keys.pending_set),
+            "processing" => Some(&self.keys.processing_set),
+            "delayed" => Some(&self.keys.delayed),
+            "done" => Some(&self.keys.done),
+            "dead" => Some(&self.keys.dead),
+            _ => None,
+        }
+    }
+
+    /// Where a task is, running tasks still being in the pending set too.
+    fn task_state(&self, redis_conn: &redis::Connection, key: &str) -> redis::RedisResult<Option<&'static str>> {
+        for &state in &["processing", "pending"] {
+            if redis_conn.sismember(self.set_of(state).unwrap(), key)? {
+                return Ok(Some(state));
+            }
+        }
+        for &state in &["delayed", "done", "dead"] {
+            let score: Option<f64> = redis_conn.zscore(self.set_of(state).unwrap(), key)?;
+            if score.is_some() {
+                return Ok(Some(state));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs `action` on the key of the task with an id, answering 404 when
+    /// no task has it.
+    fn find<F>(&self, id: &str, action: F) -> redis::RedisResult<Response>
+        where F: FnOnce(&str) -> redis::RedisResult<Response> {
+        match self.resolve(id)? {
+            Some(key) => action(&key),
+            None => Ok(error(404, "no such task")),
+        }
+    }
+
+    /// The key of the task with an id, looked up in every task set, running
+    /// tasks being pending too.
+    fn resolve(&self, id: &str) -> redis::RedisResult<Option<String>> {
+        if id.len() != 12 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let redis_conn = self.redis_client.get_connection()?;
+        let mut cursor = 0;
+        loop {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SSCAN")
+                .arg(&self.keys.pending_set).arg(cursor).arg("COUNT").arg(MAX_PAGE_SIZE)
+                .query(&redis_conn)?;
+            if let Some(key) = keys.into_iter().find(|key| payload::task_id(key) == id) {
+                return Ok(Some(key));
+            }
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        for &state in &["delayed", "done", "dead"] {
+            let mut start = 0;
+            loop {
+                let keys: Vec<String> = redis_conn.zrange(self.set_of(state).unwrap(), start, start + MAX_PAGE_SIZE as isize - 1)?;
+                let last_page = keys.len() < MAX_PAGE_SIZE;
+                if let Some(key) = keys.into_iter().find(|key| payload::task_id(key) == id) {
+                    return Ok(Some(key));
+                }
+                if last_page {
+                    break;
+                }
+                start += MAX_PAGE_SIZE as isize;
+            }
+        }
+        Ok(None)
+    }
+
+    /// A task as shown by the API, addressed by its id rather than its key,
+    /// which would give the redacted values away.
+    fn task_json(&self, key: &str, state: &str) -> Value {
+        let payload = base64::decode(key).ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .map(|payload| self.redactor.redact(&payload));
+        json!({
+            "id": payload::task_id(key),
+            "state": state,
+            "payload": payload,
+        })
+    }
+
+    /// One page of a task set, resumed from the `cursor` of the previous page.
+    /// Delayed tasks are listed from the soonest due, done and dead ones from
+    /// the most recently finished.
+    fn list_tasks(&self, request: &Request) -> redis::RedisResult<Response> {
+        let state = request.query.get("state").map_or("pending", String::as_str);
+        let set = match self.set_of(state) {
+            Some(set) => set,
+            None => return Ok(error(400, "state must be pending, processing, delayed, done or dead")),
+        };
+        let cursor = match request.query.get("cursor").map(|cursor| cursor.parse::<u64>()) {
+            Some(Ok(cursor)) => cursor,
+            Some(Err(_)) => return Ok(error(400, "invalid cursor")),
+            None => 0,
+        };
+        let count = match request.query.get("count").map(|count| count.parse::<usize>()) {
+            Some(Ok(count)) if count > 0 => count.min(MAX_PAGE_SIZE),
+            Some(_) => return Ok(error(400, "invalid count")),
+            None => PAGE_SIZE,
+        };
+
+        let redis_conn = self.redis_client.get_connection()?;
+        let (next_cursor, keys): (u64, Vec<String>) = match state {
+            "delayed" | "done" | "dead" => {
+                let (start, stop) = (cursor as isize, (cursor as usize + count) as isize - 1);
+                let keys: Vec<String> = match state {
+                    "done" | "dead" => redis_conn.zrevrange(set, start, stop)?,
+                    _ => redis_conn.zrange(set, start, stop)?,
+                };
+                match keys.len() == count {
+                    true => (cursor + count as u64, keys),
+                    false => (0, keys),
+                }
+            },
+            _ => redis::cmd("SSCAN")
+                .arg(set).arg(cursor).arg("COUNT").arg(count)
+                .query(&redis_conn)?,
+        };
+        let processing: Vec<String> = match state {
+            "pending" => redis_conn.smembers(self.keys.processing_set.as_str())?,
+            _ => Vec::new(),
+        };
+
+        let tasks: Vec<Value> = keys.iter()
+            .filter(|key| !processing.contains(key))
+            .map(|key| self.task_json(key, state))
+            .collect();
+
+        Ok(Response::json(200, &json!({
+            "state": state,
+            "tasks": tasks,
+            "cursor": match next_cursor {
+                0 => Value::Null,
+                next_cursor => Value::from(next_cursor),
+            },
+        })))
+    }
+
+    fn show_task(&self, key: &str) -> redis::RedisResult<Response> {
+        let redis_conn = self.redis_client.get_connection()?;
+        let state = match self.task_state(&redis_conn, key)? {
+            Some(state) => state,
+            None => return Ok(error(404, "no such task")),
+        };
+        let result: Option<String> = redis_conn.hget(self.keys.results.as_str(), key)?;
+
+        let mut task = self.task_json(key, state);
+        task["result"] = result
+            .and_then(|result| serde_json::from_str(&result).ok())
+            .unwrap_or(Value::Null);
+        Ok(Response::json(200, &task))
+    }
+
+    /// Queues a finished task again, as if it had just arrived.
+    fn requeue(&self, key: &str) -> redis::RedisResult<Response> {
+        let arrival = dispatcher::next_arrival(&self.redis_client.get_connection()?, &self.keys)?;
+        self.transition(key, &["done", "dead"], "pending", |pipe, keys, key| {
+            pipe.zrem(&keys.done, key).ignore()
+                .zrem(&keys.dead, key).ignore()
+                .hdel(&keys.results, key).ignore()
+                .sadd(&keys.pending_set, key).ignore()
+                .zadd(&keys.enqueued_at, key, dispatcher::unix_time()).ignore()
+                .zadd(&keys.arrival, key, arrival).ignore()
+                .publish(&keys.wakeup_channel, 1).ignore();
+        })
+    }
+
+    /// Applies `change` to a task in one of the `from` states, atomically with
+    /// respect to consumers claiming it.
+    fn transition<F>(&self, key: &str, from: &[&str], to: &str, change: F) -> redis::RedisResult<Response>
+        where F: Fn(&mut redis::Pipeline, &RedisKeys, &str) {
+        let redis_conn = self.redis_client.get_connection()?;
+        let watched = [&self.keys.processing_set, &self.keys.pending_set, &self.keys.delayed];
+        let mut state = None;
+
+        redis::transaction(&redis_conn, &watched, |pipe| {
+            state = self.task_state(&redis_conn, key)?;
+            match state {
+                Some(current) if from.contains(&current) => {
+                    change(pipe, &self.keys, key);
+                    pipe.query(&redis_conn)
+                },
+                _ => Ok(Some(())),
+            }
+        })?;
+
+        Ok(match state {
+            None => error(404, "no such task"),
+            Some(current) if from.contains(&current) => Response::json(200, &self.task_json(key, to)),
+            Some(current) => error(409, &format!("task is {}", current)),
+        })
+    }
+
+    fn show_consumer(&self) -> redis::RedisResult<Response> {
+        let redis_conn = self.redis_client.get_connection()?;
+        let paused: bool = redis_conn.exists(self.keys.paused.as_str())?;
+
+        Ok(Response::json(200, &json!({
+            "paused": paused,
+            "workers": match self.consumer {
+                true => json!({
+                    "idle": self.metrics.idle_workers.get(),
+                    "busy": self.metrics.busy_workers.get(),
+                }),
+                false => Value::Null,
+            },
+        })))
+    }
+
+    /// Consumers of the channel with a live registration.
+    fn list_consumers(&self) -> redis::RedisResult<Response> {
+        let redis_conn = self.redis_client.get_connection()?;
+        let consumers: Vec<Value> = registry::live_consumers(&redis_conn, &self.keys)?
+            .into_iter()
+            .map(|consumer| json!({
+                "id": consumer.id,
+                "host": consumer.host,
+                "pid": consumer.pid,
+                "version": consumer.version,
+                "group": consumer.group,
+                "workers": consumer.workers,
+                "started_at": consumer.started_at,
+                "heartbeat_at": consumer.heartbeat_at,
+                "in_flight": consumer.in_flight,
+            }))
+            .collect();
+
+        Ok(Response::json(200, &json!({"consumers": consumers})))
+    }
+
+    fn set_paused(&self, paused: bool) -> redis::RedisResult<Response> {
+        let redis_conn = self.redis_client.get_connection()?;
+        dispatcher::set_paused(&redis_conn, &self.keys, paused)?;
+
+        Ok(Response::json(200, &json!({"paused": paused})))
+    }
+
+    /// Takes `{"workers": n}` for a fixed size pool or
+    /// `{"min_workers": n, "max_workers": m}` for a range.
+    fn resize(&self, request: &Request) -> Response {
+        if !self.consumer {
+            return error(409, "no consumer runs in this process");
+        }
+        let body: Value = match serde_json::from_str(&request.body) {
+            Ok(body) => body,
+            Err(_) => return error(400, "expected a JSON object"),
+        };
+
+        let count = |name| body.get(name).map(|value| value.as_u64().map(|n| n as usize));
+        let (min, max) = match (count("workers"), count("min_workers"), count("max_workers")) {
+            (Some(Some(workers)), None, None) => (workers, workers),
+            (None, Some(Some(min)), Some(Some(max))) => (min, max),
+            _ => return error(400, "expected workers, or min_workers and max_workers"),
+        };
+        if min == 0 || min > max {
+            return error(400, "worker counts must be positive, min_workers up to max_workers");
+        }
+
+        self.control.request_resize(min, max);
+        Response::json(202, &json!({"min_workers": min, "max_workers": max}))
+    }
+}
+
+fn report(report: Report) -> Response {
+    let status = match report.ok() {
+        true => 200,
+        false => 503,
+    };
+    Response::text(status, report.render())
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &json!({"error": message}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use cli;
+    use dispatcher::Config;
+    use fake_redis::FakeRedis;
+    use settings::Settings;
+
+    fn request(method: &str, path: &str) -> Request {
+        Request { method: method.to_string(), path: path.to_string(), query: HashMap::new(), body: String::new() }
+    }
+
+    #[test]
+    fn admin_task_id_test() {
+        let redis = FakeRedis::start();
+        let matches = cli::create_cli_app().get_matches_from(vec![
+            "pg-dispatch", "--redis-uri", "redis_uri", "--channel", "foochan", "--mode", "consumer",
+            "--exec", "cat", "--redact", "password",
+        ]);
+        let dispatcher = Dispatcher::from_config(&Config::from_settings(&Settings::from_matches(&matches)).unwrap());
+        let admin = Admin::new(&dispatcher, redis.client()).with_api();
+        let keys = RedisKeys::for_channel("foochan");
+        let key = base64::encode(r#"{"password":"hunter2"}"#);
+        let _ : () = redis.connection().zadd(keys.dead.as_str(), key.as_str(), 1000).unwrap();
+
+        // tasks are listed by id, the key giving away the redacted password
+        let mut list = request("GET", "/api/tasks");
+        list.query.insert("state".to_string(), "dead".to_string());
+        let response = admin.handle(&list);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("[REDACTED]") && !response.body.contains(&key));
+        let id = payload::task_id(&key);
+        let response = admin.handle(&request("GET", &format!("/api/tasks/{}", id)));
+        let task: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!((task["id"].as_str(), task["payload"].as_str()), (Some(id.as_str()), Some(r#"{"password":"[REDACTED]"}"#)));
+        assert!(task.get("key").is_none());
+
+        // and changed by id
+        assert_eq!(admin.handle(&request("POST", &format!("/api/tasks/{}/requeue", id))).status, 200);
+        let pending: bool = redis.connection().sismember(keys.pending_set.as_str(), key.as_str()).unwrap();
+        assert!(pending);
+        assert_eq!(admin.handle(&request("DELETE", "/api/tasks/000000000000")).status, 404);
+        assert_eq!(admin.handle(&request("GET", &format!("/api/tasks/{}", key))).status, 404);
+    }
+}
@@ -0,0 +1,279 @@
+extern crate clap;
+extern crate redis;
+
+use std::collections::{BTreeSet, HashMap};
+use std::thread;
+use std::time::Duration;
+use dispatcher::{self, RedisKeys, FINISHED_BUCKET};
+use redis::Commands;
+use registry::{self, ConsumerInfo};
+use settings::Settings;
+
+/// Seconds between refreshes of `status --watch`.
+const WATCH_INTERVAL: u64 = 2;
+
+/// Runs a subcommand, returning the process exit code.
+pub fn run(name: &str, matches: &clap::ArgMatches) -> i32 {
+    let result = Settings::load(matches).and_then(|settings| match name {
+        "pause" => set_paused(&settings, true),
+        "resume" => set_paused(&settings, false),
+        "status" => status(&settings, matches.is_present("watch")),
+        _ => Err(format!("unknown command {}", name)),
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("{}.", error);
+            1
+        }
+    }
+}
+
+fn connect_redis(settings: &Settings) -> Result<redis::Connection, String> {
+    let redis_url = settings.require("redis-uri")?;
+    redis::Client::open(redis_url.as_str())
+        .and_then(|client| client.get_connection())
+        .map_err(|error| format!("Failed to connect to Redis: {:?}", error))
+}
+
+fn set_paused(settings: &Settings, paused: bool) -> Result<(), String> {
+    let channel = settings.require("channel")?;
+    let redis_conn = connect_redis(settings)?;
+
+    dispatcher::set_paused(&redis_conn, &RedisKeys::for_channel(&channel), paused)
+        .map_err(|error| format!("Failed to update the pause flag: {:?}", error))?;
+    match paused {
+        true => println!("Paused channel {}, notifications keep being queued.", channel),
+        false => println!("Resumed channel {}.", channel),
+    }
+    Ok(())
+}
+
+/// Prints the state of a channel's queue, or of every channel found in Redis.
+fn status(settings: &Settings, watch: bool) -> Result<(), String> {
+    let redis_conn = connect_redis(settings)?;
+
+    loop {
+        let channels = match settings.value_of("channel") {
+            Some(channel) => vec![channel],
+            None => find_channels(&redis_conn)
+                .map_err(|error| format!("Failed to list channels: {:?}", error))?,
+        };
+
+        let mut report = String::new();
+        for channel in &channels {
+            let status = ChannelStatus::read(&redis_conn, channel)
+                .map_err(|error| format!("Failed to read channel {}: {:?}", channel, error))?;
+            report.push_str(&status.render());
+        }
+        if channels.is_empty() {
+            report.push_str("No channels found.\n");
+        }
+
+        if !watch {
+            print!("{}", report);
+            return Ok(());
+        }
+        // clear the screen before each refresh
+        print!("\x1b[2J\x1b[H{}", report);
+        thread::sleep(Duration::from_secs(WATCH_INTERVAL));
+    }
+}
+
+/// Channels with any task set or registered consumer in Redis, Redis
+/// dropping the sets that are emptied.
+fn find_channels(redis_conn: &redis::Connection) -> redis::RedisResult<Vec<String>> {
+    let mut channels = BTreeSet::new();
+    let mut cursor = 0;
+
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor).arg("MATCH").arg("dispatcher:*").arg("COUNT").arg(1000)
+            .query(redis_conn)?;
+        for key in keys {
+            let channel = key.trim_start_matches("dispatcher:").rsplit_once(':').map(|(channel, _)| channel);
+            if let Some(channel) = channel {
+                let keys = RedisKeys::for_channel(channel);
+                if [&keys.pending_set, &keys.processing_set, &keys.delayed, &keys.done, &keys.dead, &keys.consumers]
+                    .iter().any(|set| **set == key) {
+                    channels.insert(channel.to_string());
+                }
+            }
+        }
+        if next_cursor == 0 {
+            return Ok(channels.into_iter().collect());
+        }
+        cursor = next_cursor;
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelStatus {
+    channel: String,
+    paused: bool,
+    pending: usize,
+    processing: usize,
+    delayed: usize,
+    done: usize,
+    dead: usize,
+    /// Seconds the oldest task not running yet has been waiting.
+    oldest_pending: Option<u64>,
+    /// Tasks done and dead over the last minute.
+    last_minute: (usize, usize),
+    consumers: Vec<ConsumerInfo>,
+    /// Unix time the status was read at.
+    read_at: u64,
+}
+
+impl ChannelStatus {
+    fn read(redis_conn: &redis::Connection, channel: &str) -> redis::RedisResult<ChannelStatus> {
+        let keys = RedisKeys::for_channel(channel);
+        let now = dispatcher::unix_time();
+        let processing: usize = redis_conn.scard(keys.processing_set.as_str())?;
+        // running tasks stay in the pending set until they finish
+        let pending: usize = redis_conn.scard(keys.pending_set.as_str())?;
+        let oldest_pending = dispatcher::oldest_pending(redis_conn, &keys)?
+            .map(|enqueued_at| now.saturating_sub(enqueued_at));
+
+        Ok(ChannelStatus {
+            channel: channel.to_string(),
+            paused: redis_conn.exists(keys.paused.as_str())?,
+            pending: pending.saturating_sub(processing),
+            processing,
+            delayed: redis_conn.zcard(keys.delayed.as_str())?,
+            done: redis_conn.zcard(keys.done.as_str())?,
+            dead: redis_conn.zcard(keys.dead.as_str())?,
+            oldest_pending,
+            last_minute: last_minute(redis_conn, &keys, now)?,
+            consumers: registry::live_consumers(redis_conn, &keys)?,
+            read_at: now,
+        })
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!(
+            "channel {}{}\n",
+            self.channel, if self.paused { " (paused)" } else { "" });
+        out.push_str(&format!("  pending     {:>8}", self.pending));
+        if let Some(age) = self.oldest_pending {
+            out.push_str(&format!("   oldest {}", format_age(age)));
+        }
+        out.push('\n');
+        out.push_str(&format!("  processing  {:>8}\n", self.processing));
+        out.push_str(&format!("  delayed     {:>8}\n", self.delayed));
+        out.push_str(&format!("  done        {:>8}\n", self.done));
+        out.push_str(&format!("  dead        {:>8}\n", self.dead));
+        out.push_str(&format!(
+            "  last minute {:>8} done, {} dead ({:.2}/s)\n",
+            self.last_minute.0, self.last_minute.1,
+            (self.last_minute.0 + self.last_minute.1) as f64 / 60.0));
+        out.push_str(&format!("  consumers   {:>8}\n", self.consumers.len()));
+        for consumer in &self.consumers {
+            out.push_str(&format!(
+                "    {} ({} v{}) {} workers, {} in flight, up {}, heartbeat {} ago\n",
+                consumer.id, consumer.group, consumer.version, consumer.workers,
+                consumer.in_flight.len(),
+                format_age(self.read_at.saturating_sub(consumer.started_at)),
+                format_age(self.read_at.saturating_sub(consumer.heartbeat_at))));
+        }
+        out
+    }
+}
+
+/// Tasks done and dead over the 60 seconds before the current bucket,
+/// which is still filling up.
+fn last_minute(redis_conn: &redis::Connection, keys: &RedisKeys, now: u64) -> redis::RedisResult<(usize, usize)> {
+    let mut last_minute = (0, 0);
+    for bucket in (now - 60) / FINISHED_BUCKET..now / FINISHED_BUCKET {
+        let counts: HashMap<String, usize> =
+            redis_conn.hgetall(keys.finished_bucket(bucket * FINISHED_BUCKET))?;
+        last_minute.0 += counts.get("done").cloned().unwrap_or(0);
+        last_minute.1 += counts.get("dead").cloned().unwrap_or(0);
+    }
+    Ok(last_minute)
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_redis::FakeRedis;
+
+    #[test]
+    fn channel_status_render_test() {
+        let status = ChannelStatus {
+            channel: "foochan".to_string(),
+            paused: true,
+            pending: 12,
+            processing: 4,
+            done: 1520,
+            dead: 3,
+            oldest_pending: Some(192),
+            last_minute: (118, 2),
+            consumers: vec![ConsumerInfo {
+                id: "web-1-12".to_string(),
+                host: "web-1".to_string(),
+                pid: 12,
+                version: "0.1.0".to_string(),
+                channel: "foochan".to_string(),
+                group: "default".to_string(),
+                workers: 4,
+                started_at: 1000,
+                heartbeat_at: 4597,
+                in_flight: vec!["Zm9v".to_string()],
+            }],
+            read_at: 4600,
+            ..ChannelStatus::default()
+        };
+
+        assert_eq!(status.render(), "\
+channel foochan (paused)
+  pending           12   oldest 3m 12s
+  processing         4
+  delayed            0
+  done            1520
+  dead               3
+  last minute      118 done, 2 dead (2.00/s)
+  consumers          1
+    web-1-12 (default v0.1.0) 4 workers, 1 in flight, up 1h 00m, heartbeat 3s ago
+");
+        assert_eq!(format_age(7260), "2h 01m");
+    }
+
+    #[test]
+    fn last_minute_test() {
+        let redis = FakeRedis::start();
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        // one done task every 10 seconds from 1000 to 1100
+        for time in (1000..=1100).step_by(10) {
+            let _ : () = redis_conn.hincr(keys.finished_bucket(time), "done", 1).unwrap();
+        }
+        let _ : () = redis_conn.hincr(keys.finished_bucket(1095), "dead", 1).unwrap();
+
+        // 1040 to 1099, leaving out the bucket of 1100 still filling up
+        assert_eq!(last_minute(&redis_conn, &keys, 1105).unwrap(), (6, 1));
+    }
+
+    #[test]
+    fn find_channels_test() {
+        let redis = FakeRedis::start();
+        let redis_conn = redis.connection();
+        let _ : () = redis_conn.sadd(RedisKeys::for_channel("busy").pending_set.as_str(), "Zm9v").unwrap();
+        let _ : () = redis_conn.zadd(RedisKeys::for_channel("drained").done.as_str(), "Zm9v", 1000).unwrap();
+        let _ : () = redis_conn.zadd(RedisKeys::for_channel("failing").dead.as_str(), "Zm9v", 1000).unwrap();
+        let _ : () = redis_conn.zadd(RedisKeys::for_channel("scheduled").delayed.as_str(), "Zm9v", 1000).unwrap();
+        let idle = RedisKeys::for_channel("idle");
+        let _ : () = redis_conn.zadd(idle.consumers.as_str(), "web-1", 1000).unwrap();
+        let _ : () = redis_conn.hset(idle.consumer("web-1").as_str(), "workers", 4).unwrap();
+
+        assert_eq!(find_channels(&redis_conn).unwrap(), vec!["busy", "drained", "failing", "idle", "scheduled"]);
+    }
+}
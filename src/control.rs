@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use dispatcher::Config;
+use thread_pool::Waker;
+
+/// Requests sent to the running producer and consumer from outside their
+/// threads, such as from signal handlers and the admin API.
+#[derive(Debug, Default)]
+pub struct Control {
+    shutdown: AtomicBool,
+    reload: Mutex<Option<Config>>,
+    producer_reload: Mutex<Option<Config>>,
+    resize: Mutex<Option<(usize, usize)>>,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Control {
+    pub fn new() -> Arc<Control> {
+        Arc::new(Control::default())
+    }
+
+    /// Asks producer and consumer to stop, waking any consumer waiting for work.
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Hands a freshly loaded configuration to the consumer and the producer.
+    pub fn request_reload(&self, config: Config) {
+        *self.producer_reload.lock().unwrap() = Some(config.clone());
+        *self.reload.lock().unwrap() = Some(config);
+        self.wake_all();
+    }
+
+    pub fn take_reload(&self) -> Option<Config> {
+        self.reload.lock().unwrap().take()
+    }
+
+    pub fn take_producer_reload(&self) -> Option<Config> {
+        self.producer_reload.lock().unwrap().take()
+    }
+
+    /// Asks the consumer to keep between `min` and `max` workers.
+    pub fn request_resize(&self, min: usize, max: usize) {
+        *self.resize.lock().unwrap() = Some((min, max));
+        self.wake_all();
+    }
+
+    pub fn take_resize(&self) -> Option<(usize, usize)> {
+        self.resize.lock().unwrap().take()
+    }
+
+    /// Registers a pool to be woken up when a request arrives.
+    pub fn register(&self, waker: Waker) {
+        self.wakers.lock().unwrap().push(waker);
+    }
+
+    fn wake_all(&self) {
+        for waker in self.wakers.lock().unwrap().iter() {
+            waker.wake();
+        }
+    }
+}
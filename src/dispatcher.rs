@@ -1,16 +1,56 @@
-extern crate clap;
 extern crate postgres;
 extern crate redis;
 extern crate fallible_iterator;
 extern crate base64;
+extern crate serde_json;
 
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
+use std::sync::Arc;
 use self::fallible_iterator::FallibleIterator;
-use thread_pool::{ThreadPool, WorkerMessage};
+use control::Control;
+use health::Health;
+use log;
+use metrics::Metrics;
+use payload::{self, Redactor};
+use rate_limit::{Rate, RateLimiter};
+use filter::Filter;
+use registry::{self, ConsumerInfo};
+use routes::{Route, Routes};
+use settings::Settings;
+use persistent::PersistentExecutor;
+use sql::SqlExecutor;
+use postgres::TlsMode;
+use postgres::tls::native_tls::NativeTls;
+use executor::{CommandExecutor, Executor, Outcome};
+use thread_pool::{ThreadPool, Waker, WorkerMessage};
+use webhook::Webhook;
 use std::str;
 use std::process::exit;
 use std::{thread, time};
-use redis::Commands;
+use redis::{Commands, PipelineCommands};
+
+/// How long an idle consumer waits for a wake-up before scanning the pending
+/// set anyway, covering wake-ups lost while disconnected from Redis.
+const RESCAN_INTERVAL: u64 = 5;
+
+/// How often the done tasks past the retention limits are pruned.
+const PRUNE_INTERVAL: u64 = 60;
+
+/// Keys removed from Redis per command when pruning or migrating.
+const PRUNE_BATCH: usize = 1000;
+
+/// Oldest arrivals read per claim in FIFO order, rather than the whole
+/// backlog.
+const ARRIVAL_WINDOW: usize = 1000;
+
+/// Seconds covered by each counter of finished tasks.
+pub const FINISHED_BUCKET: u64 = 10;
+
+/// How long aborted tasks get to report back before their keys are released
+/// without confirmation.
+const ABORT_TIMEOUT: u64 = 5;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -19,183 +59,1485 @@ pub struct Config {
     pub db_channel: String,
     pub consumer: bool,
     pub producer: bool,
+    pub min_threads: usize,
     pub max_threads: usize,
+    pub scale_down_after: u64,
     pub tls_mode: String,
     pub command_vector: Vec<OsString>,
+    /// Endpoint the payloads are POSTed to instead of running `command_vector`.
+    pub webhook: Option<Webhook>,
+    /// Statement run with the payloads instead of `command_vector`.
+    pub sql: Option<SqlExecutor>,
+    /// Long-lived children of `command_vector` running the payloads, instead
+    /// of a command per payload.
+    pub persistent: Option<PersistentExecutor>,
+    /// Seconds before trying a task again when its handler failed in a way
+    /// worth retrying.
+    pub retry_delay: u64,
+    /// Runs of a task before it is given up on as dead, 0 for no limit.
+    pub max_attempts: u64,
+    /// Commands for the payloads matching a rule, the default job running the
+    /// others.
+    pub routes: Vec<Route>,
+    /// Conditions notifications have to meet to be enqueued.
+    pub filters: Vec<Filter>,
+    pub drain_timeout: u64,
+    pub metrics_addr: Option<String>,
+    /// Tells apart consumers of the channel, e.g. running different commands.
+    pub consumer_group: String,
+    /// Seconds done tasks are remembered for.
+    pub done_ttl: Option<u64>,
+    /// Most recent done tasks remembered, none being recorded at 0.
+    pub done_max_entries: Option<usize>,
+    /// Seconds dead tasks are remembered for, forever when unset.
+    pub dead_ttl: Option<u64>,
+    /// Most recent dead tasks remembered, none being recorded at 0.
+    pub dead_max_entries: Option<usize>,
+    pub admin_addr: Option<String>,
+    /// Whether pending tasks are claimed in arrival order.
+    pub fifo: bool,
+    /// JSON path of the payload timestamp a task is delayed until, `$.run_at`
+    /// unless set empty, tasks running as they arrive without one.
+    pub run_at_key: Option<String>,
+    /// JSON path of the payload value giving the priority of a task.
+    pub priority_key: Option<String>,
+    /// Priority of the tasks without one in their payload.
+    pub priority: i64,
+    /// Seconds of waiting worth a priority level, 0 for none.
+    pub priority_aging: u64,
+    /// JSON path of the payload value tasks must not run concurrently with,
+    /// and must run in arrival order with.
+    pub concurrency_key: Option<String>,
+    /// Most commands started per period.
+    pub rate_limit: Option<Rate>,
+    /// Whether the rate limit is shared by every consumer of the channel.
+    pub rate_limit_shared: bool,
+    pub log_level: log::Level,
+    pub log_format: log::Format,
+    pub redactor: Redactor,
 }
 
 impl Config {
-    pub fn from_matches(matches: &clap::ArgMatches) -> Config {
-        Config {
-            db_url: matches.value_of("db-uri").unwrap().to_string(),
-            redis_url: matches.value_of("redis-uri").unwrap().to_string(),
-            consumer: match matches.value_of("mode") {
-                Some("producer") => false,
-                Some(_) | None => true
+    pub fn from_settings(settings: &Settings) -> Result<Config, String> {
+        let mode = settings.value_of("mode");
+        let producer = match mode.as_deref() {
+            Some("consumer") => false,
+            Some(_) | None => true
+        };
+
+        // a fixed size pool unless a range is given
+        let max_threads = match settings.value_of("max-workers").or(settings.value_of("workers")) {
+            Some(v) => v.parse::<usize>().unwrap_or(4),
+            _ => 4,
+        }.max(1);
+        let executors = ["exec", "http-post", "sql"].iter()
+            .filter(|executor| settings.value_of(executor).is_some())
+            .count();
+        if executors > 1 {
+            return Err("--exec, --http-post and --sql are alternatives, use only one".to_string());
+        }
+        // routed payloads run their command once each
+        let routed_elsewhere = ["http-post", "sql"].iter().any(|executor| settings.value_of(executor).is_some())
+            || settings.flag("persistent");
+        if routed_elsewhere && !settings.values_of("route").is_empty() {
+            return Err("--route runs commands, and cannot be combined with --http-post, --sql or --persistent".to_string());
+        }
+        let sql = settings.value_of("sql");
+
+        let min_threads = match settings.value_of("min-workers") {
+            Some(v) => v.parse::<usize>().unwrap_or(1),
+            None if settings.value_of("max-workers").is_some() => 1,
+            None => max_threads,
+        }.max(1);
+        if min_threads > max_threads {
+            return Err(format!("--min-workers {} is more than the {} workers at most, raise --max-workers",
+                               min_threads, max_threads));
+        }
+
+        Ok(Config {
+            db_url: match producer || sql.is_some() {
+                true => settings.require("db-uri")?,
+                false => settings.value_of("db-uri").unwrap_or_default(),
             },
-            producer: match matches.value_of("mode") {
-                Some("consumer") => false,
+            redis_url: settings.require("redis-uri")?,
+            consumer: match mode.as_deref() {
+                Some("producer") => false,
                 Some(_) | None => true
             },
-            tls_mode: matches.value_of("tls-mode").unwrap_or("none").to_string(),
-            db_channel: matches.value_of("channel").unwrap().to_string(),
-            max_threads: match matches.value_of("workers") {
-                Some(v) => v.parse::<usize>().unwrap_or(4),
-                _ => 4,
+            producer,
+            tls_mode: settings.value_of("tls-mode").unwrap_or("none".to_string()),
+            db_channel: settings.require("channel")?,
+            min_threads,
+            max_threads,
+            scale_down_after: match settings.value_of("scale-down-after") {
+                Some(v) => v.parse::<u64>().unwrap_or(60),
+                _ => 60,
             },
-            command_vector: matches.value_of("exec")
-                .unwrap()
+            command_vector: match executors {
+                0 => settings.require("exec")?,
+                _ => settings.value_of("exec").unwrap_or_default(),
+            }
                 .split_whitespace()
-                .map(|s| OsString::from(s))
+                .map(OsString::from)
                 .collect(),
+            webhook: match settings.value_of("http-post") {
+                Some(url) => Some(Webhook::new(
+                    url,
+                    settings.values_of("http-header").iter()
+                        .map(|header| Webhook::parse_header(header))
+                        .collect::<Result<_, _>>()?,
+                    time::Duration::from_secs(match settings.value_of("http-timeout") {
+                        Some(v) => v.parse::<u64>().map_err(|_| "--http-timeout must be a number of seconds")?,
+                        None => 30,
+                    }),
+                    settings.value_of("http-secret"))),
+                None => None,
+            },
+            sql: match sql {
+                Some(statement) => Some(SqlExecutor::new(
+                    statement,
+                    settings.require("db-uri")?,
+                    settings.value_of("tls-mode").unwrap_or("none".to_string()),
+                    match settings.value_of("sql-pool-size") {
+                        Some(v) => v.parse::<usize>().map_err(|_| "--sql-pool-size must be a number")?,
+                        None => 2,
+                    },
+                    match settings.value_of("sql-timeout") {
+                        Some(v) => v.parse::<u64>().map_err(|_| "--sql-timeout must be a number of seconds")?,
+                        None => 30,
+                    },
+                    settings.value_of("sql-retry-states")
+                        .unwrap_or("08,40001,40P01,53,55P03,57P01".to_string())
+                        .split(',')
+                        .map(|state| state.trim().to_uppercase())
+                        .filter(|state| !state.is_empty())
+                        .collect())),
+                None => None,
+            },
+            persistent: match settings.flag("persistent") {
+                true => Some(PersistentExecutor::new(
+                    settings.require("exec").map_err(|_| "--persistent keeps --exec commands running")?
+                        .split_whitespace()
+                        .map(OsString::from)
+                        .collect(),
+                    match settings.value_of("persistent-children") {
+                        Some(v) => v.parse::<usize>().map_err(|_| "--persistent-children must be a number")?,
+                        None => 0,
+                    },
+                    match settings.value_of("persistent-max-tasks") {
+                        Some(v) => v.parse::<usize>().map_err(|_| "--persistent-max-tasks must be a number")?,
+                        None => 1000,
+                    },
+                    match settings.value_of("persistent-timeout") {
+                        Some(v) => Some(time::Duration::from_secs(
+                            v.parse::<u64>().map_err(|_| "--persistent-timeout must be a number of seconds")?)),
+                        None => None,
+                    })),
+                false => None,
+            },
+            retry_delay: match settings.value_of("retry-delay") {
+                Some(v) => v.parse::<u64>().map_err(|_| "--retry-delay must be a number of seconds")?,
+                None => 10,
+            },
+            max_attempts: match settings.value_of("max-attempts") {
+                Some(v) => v.parse::<u64>().map_err(|_| "--max-attempts must be a number")?,
+                None => 10,
+            },
+            routes: settings.values_of("route").iter()
+                .map(|route| Route::parse(route))
+                .collect::<Result<_, _>>()?,
+            filters: settings.values_of("filter").iter()
+                .map(|filter| Filter::parse(filter))
+                .collect::<Result<_, _>>()?,
+            drain_timeout: match settings.value_of("drain-timeout") {
+                Some(v) => v.parse::<u64>().unwrap_or(30),
+                _ => 30,
+            },
+            metrics_addr: settings.value_of("metrics-addr"),
+            consumer_group: settings.value_of("group").unwrap_or("default".to_string()),
+            done_ttl: match settings.value_of("done-ttl") {
+                Some(v) => Some(v.parse::<u64>().map_err(|_| "--done-ttl must be a number of seconds")?),
+                None => None,
+            },
+            done_max_entries: match settings.value_of("done-max-entries") {
+                Some(v) => Some(v.parse::<usize>().map_err(|_| "--done-max-entries must be a number")?),
+                None => None,
+            },
+            dead_ttl: match settings.value_of("dead-ttl") {
+                Some(v) => Some(v.parse::<u64>().map_err(|_| "--dead-ttl must be a number of seconds")?),
+                None => None,
+            },
+            dead_max_entries: match settings.value_of("dead-max-entries") {
+                Some(v) => Some(v.parse::<usize>().map_err(|_| "--dead-max-entries must be a number")?),
+                None => None,
+            },
+            admin_addr: settings.value_of("admin-addr"),
+            fifo: match settings.value_of("order").as_deref() {
+                Some("fifo") => true,
+                Some("any") | None => false,
+                Some(order) => return Err(format!("unknown claim order {}", order)),
+            },
+            run_at_key: match settings.value_of("run-at-key") {
+                Some(path) => Some(path).filter(|path| !path.is_empty()),
+                None => Some("$.run_at".to_string()),
+            },
+            priority_key: settings.value_of("priority-key"),
+            priority: match settings.value_of("priority") {
+                Some(v) => v.parse::<i64>().map_err(|_| "--priority must be a whole number")?,
+                None => 0,
+            },
+            priority_aging: match settings.value_of("priority-aging") {
+                Some(v) => v.parse::<u64>().map_err(|_| "--priority-aging must be a number of seconds")?,
+                None => 60,
+            },
+            concurrency_key: settings.value_of("concurrency-key"),
+            rate_limit: match settings.value_of("rate-limit") {
+                Some(v) => Some(v.parse()?),
+                None => None,
+            },
+            rate_limit_shared: match settings.value_of("rate-limit-scope").as_deref() {
+                Some("channel") => true,
+                Some("consumer") | None => false,
+                Some(scope) => return Err(format!("unknown rate limit scope {}", scope)),
+            },
+            log_level: settings.value_of("log-level").unwrap_or("info".to_string()).parse()?,
+            log_format: settings.value_of("log-format").unwrap_or("text".to_string()).parse()?,
+            redactor: Redactor::new(
+                settings.value_of("log-payloads").unwrap_or("none".to_string()).parse()?,
+                &settings.value_of("redact").unwrap_or_default().split(',').collect::<Vec<_>>()),
+        })
+    }
+}
+
+/// Redis keys holding the task sets of a channel.
+#[derive(Debug, Clone)]
+pub struct RedisKeys {
+    pub pending_set: String,
+    /// Sorted set of pending task keys, scored by the time they were queued.
+    pub enqueued_at: String,
+    pub processing_set: String,
+    /// Sorted set of pending task keys, scored by their order of arrival.
+    pub arrival: String,
+    /// Counter numbering the tasks in their order of arrival.
+    pub arrival_seq: String,
+    /// Hash of concurrency key value to the task running with it.
+    pub concurrency: String,
+    /// Sorted set of tasks to run later, scored by the unix time they are due.
+    pub delayed: String,
+    /// Sorted set of finished task keys, scored by completion time.
+    pub done: String,
+    /// Set that held the finished task keys before `done`.
+    pub legacy_done_set: String,
+    /// Sorted set of tasks whose command failed, or that were cancelled,
+    /// scored by the time they finished.
+    pub dead: String,
+    /// Hash of task key to a JSON object describing how it finished.
+    pub results: String,
+    /// Hash of task key to the number of times it was claimed.
+    pub attempts: String,
+    /// Set while consumers must not claim new tasks.
+    pub paused: String,
+    pub wakeup_channel: String,
+    /// Sorted set of registered consumer ids, scored by their last heartbeat.
+    pub consumers: String,
+    /// Hash holding the token bucket of a rate limit shared by the consumers.
+    pub rate_limit: String,
+    /// Prefix of the hashes counting the tasks finished in each
+    /// `FINISHED_BUCKET` seconds.
+    pub finished: String,
+}
+
+impl RedisKeys {
+    pub fn for_channel(channel: &str) -> RedisKeys {
+        RedisKeys {
+            pending_set: format!("dispatcher:{}:pending_set", channel),
+            enqueued_at: format!("dispatcher:{}:enqueued_at", channel),
+            processing_set: format!("dispatcher:{}:processing_set", channel),
+            arrival: format!("dispatcher:{}:arrival", channel),
+            arrival_seq: format!("dispatcher:{}:arrival_seq", channel),
+            concurrency: format!("dispatcher:{}:concurrency", channel),
+            delayed: format!("dispatcher:{}:delayed", channel),
+            done: format!("dispatcher:{}:done", channel),
+            legacy_done_set: format!("dispatcher:{}:done_set", channel),
+            dead: format!("dispatcher:{}:dead", channel),
+            results: format!("dispatcher:{}:results", channel),
+            attempts: format!("dispatcher:{}:attempts", channel),
+            paused: format!("dispatcher:{}:paused", channel),
+            wakeup_channel: format!("dispatcher:{}:wakeup", channel),
+            consumers: format!("dispatcher:{}:consumers", channel),
+            rate_limit: format!("dispatcher:{}:rate_limit", channel),
+            finished: format!("dispatcher:{}:finished", channel),
         }
     }
+
+    /// Hash describing a registered consumer.
+    pub fn consumer(&self, id: &str) -> String {
+        format!("{}:{}", self.consumers, id)
+    }
+
+    /// Set of the task keys a registered consumer is running.
+    pub fn consumer_in_flight(&self, id: &str) -> String {
+        format!("{}:{}:in_flight", self.consumers, id)
+    }
+
+    /// Counters of the tasks finished around `time`, in unix seconds.
+    pub fn finished_bucket(&self, time: u64) -> String {
+        format!("{}:{}", self.finished, time / FINISHED_BUCKET)
+    }
+}
+
+/// Stops or resumes claiming on every consumer of a channel. Notifications
+/// keep being queued while paused.
+pub fn set_paused(redis_conn: &redis::Connection, keys: &RedisKeys, paused: bool) -> redis::RedisResult<()> {
+    match paused {
+        true => redis_conn.set(keys.paused.as_str(), 1),
+        false => {
+            let _ : () = redis_conn.del(keys.paused.as_str())?;
+            redis_conn.publish(keys.wakeup_channel.as_str(), 1)
+        },
+    }
+}
+
+/// When the oldest task not running yet was queued, in unix seconds.
+pub fn oldest_pending(redis_conn: &redis::Connection, keys: &RedisKeys) -> redis::RedisResult<Option<u64>> {
+    let processing: Vec<String> = redis_conn.smembers(keys.processing_set.as_str())?;
+    let oldest: Vec<(String, u64)> = redis_conn.zrange_withscores(
+        keys.enqueued_at.as_str(), 0, processing.len() as isize)?;
+    Ok(oldest.into_iter()
+        .find(|(key, _)| !processing.contains(key))
+        .map(|(_, enqueued_at)| enqueued_at))
+}
+
+/// The executors payloads run with: their route, else the webhook, the
+/// statement or the command, kept running when persistent.
+fn routes(config: &Config) -> Routes {
+    let default: Arc<dyn Executor> = match (config.webhook.as_ref(), config.sql.as_ref(), config.persistent.as_ref()) {
+        (Some(webhook), _, _) => Arc::new(webhook.clone()),
+        (_, Some(sql), _) => Arc::new(sql.clone()),
+        (_, _, Some(persistent)) => Arc::new(persistent.clone()),
+        _ => Arc::new(CommandExecutor::new(config.command_vector.clone())),
+    };
+    Routes::new(config.routes.clone(), default)
+}
+
+/// Next number in the order tasks arrive in.
+pub fn next_arrival(redis_conn: &redis::Connection, keys: &RedisKeys) -> redis::RedisResult<u64> {
+    redis_conn.incr(keys.arrival_seq.as_str(), 1)
 }
 
 #[derive(Debug)]
 pub struct Dispatcher {
     pub config: Config,
+    pub control: Arc<Control>,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
 }
 
 impl Dispatcher {
     pub fn from_config(config: &Config) -> Dispatcher {
         Dispatcher {
-            config: config.clone()
+            config: config.clone(),
+            control: Control::new(),
+            metrics: Arc::new(Metrics::new(&config.db_channel)),
+            health: Arc::new(Health::new(config.producer, config.consumer)),
         }
     }
 
     pub fn start_consumer(&self, redis_client: redis::Client) -> thread::JoinHandle<()> {
-        {
-            let config = self.config.clone();
-            let redis_conn = redis_client.get_connection().unwrap();
-            let pending_set = format!("dispatcher:{}:pending_set", &config.db_channel);
-            let processing_set = format!("dispatcher:{}:processing_set", &config.db_channel);
-            let done_set = format!("dispatcher:{}:done_set", &config.db_channel);
-
-            let handler = thread::spawn(move||{
-                let pool = ThreadPool::new(
-                    config.max_threads,
-                    config.command_vector.clone());
-
-                println!(
-                    "[pg-dispatcher-consumer] Start consumer for payloads of channel {}",
-                    config.db_channel);
-
-                loop {
-                    let guard_idle_counter = pool.idle_counter.clone();
-                    let guard_counter: usize;
-
-                    {
-                        let counter = match guard_idle_counter.lock() {
-                            Ok(count) => count,
-                            Err(p) => p.into_inner()
-                        };
-
-                        guard_counter = *counter;
-                    }
+        let consumer = Consumer::new(self, redis_client);
+        self.health.consumer_started();
+        thread::spawn(move|| consumer.run())
+    }
 
-                    if let Ok(worker_output) = pool.workers_channel.try_recv() {
-                        match worker_output {
-                            WorkerMessage::ProgramNotFound(b64_key)
-                                | WorkerMessage::StdinFailed(b64_key) => {
-                                let _ : Result<(),_> = redis_conn.
-                                    srem(processing_set.clone(), b64_key);
-                            },
-                            WorkerMessage::DoneTask(b64_key) => {
-                                // add to done task
-                                // TODO: add some task to cleanup the done set
-                                let _ : Result<(), _> = redis_conn.
-                                    sadd(done_set.clone(), b64_key.clone());
-
-                                // remove from pending set
-                                let _ : Result<(),_> = redis_conn.
-                                    srem(pending_set.clone(), b64_key.clone());
-
-                                // remove from processing set
-                                let _ : Result<(),_> = redis_conn.
-                                    srem(processing_set.clone(), b64_key.clone());
-                            }
-                        }
-                    }
+    pub fn start_producer(&self, pg_conn: postgres::Connection, redis_client: redis::Client) -> thread::JoinHandle<()> {
+        if let Err(error) = pg_conn.execute(&format!("LISTEN {}", self.config.db_channel), &[]) {
+            log::error("failed to execute LISTEN command in database")
+                .field("error", error.to_string())
+                .emit();
+            exit(1)
+        }
 
-                    let diff_result : Result<Vec<String>, _> = redis_conn
-                        .sdiff(&[pending_set.clone(), processing_set.clone()]);
-
-                    if let Ok(diff) = diff_result {
-                        for (i, key) in diff.iter().enumerate() {
-                            if i+1 > guard_counter { break; }
-                            let decoded = base64::decode(&key).unwrap();
-
-                            if let Ok(payload) = str::from_utf8(&decoded) {
-                                match redis_conn.sadd(processing_set.clone(), key) {
-                                    Ok(1) => {
-                                        println!("[pg-dispatcher-consumer] start processing key {}", &key);
-                                        pool.execute(payload.to_string())
-                                    },
-                                    _ => {}
-                                };
-                            }
-                        }
-                    }
+        let producer = Producer::new(self, redis_client);
+        self.health.producer_started();
+        self.health.set_listening(true);
+        thread::spawn(move|| producer.run(pg_conn))
+    }
+}
+
+/// Connects to the database with the configured TLS mode.
+pub fn connect_postgres(config: &Config) -> postgres::Result<postgres::Connection> {
+    connect(&config.db_url, &config.tls_mode)
+}
+
+/// Connects to a database with a TLS mode of none, prefer or require.
+pub fn connect(db_url: &str, tls_mode: &str) -> postgres::Result<postgres::Connection> {
+    let negotiator = NativeTls::new().unwrap();
+    let tls_mode : TlsMode = match tls_mode {
+        "prefer" => { TlsMode::Prefer(&negotiator) },
+        "require" => { TlsMode::Require(&negotiator) },
+        _ => { TlsMode::None },
+    };
+    postgres::Connection::connect(db_url, tls_mode)
+}
+
+/// Claims pending tasks of a channel and runs them on a `ThreadPool`.
+struct Consumer {
+    config: Config,
+    keys: RedisKeys,
+    redis_client: redis::Client,
+    redis_conn: redis::Connection,
+    pool: ThreadPool,
+    /// Keys of the tasks handed to the pool and not reported back yet.
+    in_flight: HashSet<String>,
+    /// Whether the channel was paused on the last claim.
+    paused: bool,
+    rate_limiter: Option<RateLimiter>,
+    /// How long until the rate limit lets the next task start, when it
+    /// stopped the last claim short.
+    throttled_for: Option<time::Duration>,
+    /// How long until the next delayed task is due.
+    next_due: Option<time::Duration>,
+    next_prune: time::Instant,
+    /// How this consumer shows up in the registry.
+    registration: ConsumerInfo,
+    next_heartbeat: time::Instant,
+    control: Arc<Control>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+}
+
+impl Consumer {
+    fn new(dispatcher: &Dispatcher, redis_client: redis::Client) -> Consumer {
+        let config = &dispatcher.config;
+        let keys = RedisKeys::for_channel(&config.db_channel);
+        Consumer {
+            config: config.clone(),
+            rate_limiter: rate_limiter(config, &keys),
+            throttled_for: None,
+            next_due: None,
+            keys,
+            redis_conn: redis_client.get_connection().unwrap(),
+            redis_client,
+            pool: ThreadPool::new(config.min_threads, routes(config)),
+            in_flight: HashSet::new(),
+            paused: false,
+            next_prune: time::Instant::now(),
+            registration: ConsumerInfo::current(&config.db_channel, &config.consumer_group, config.min_threads),
+            next_heartbeat: time::Instant::now(),
+            control: dispatcher.control.clone(),
+            metrics: dispatcher.metrics.clone(),
+            health: dispatcher.health.clone(),
+        }
+    }
+
+    fn run(mut self) {
+        log::info("consumer started")
+            .field("consumer", self.registration.id.as_str())
+            .field("group", self.config.consumer_group.as_str())
+            .field("rate_limit", self.config.rate_limit.map(|rate| rate.to_string()))
+            .emit();
+
+        self.migrate_done_set();
+        self.control.register(self.pool.waker());
+        spawn_wakeup_listener(
+            self.redis_client.clone(), self.keys.wakeup_channel.clone(),
+            self.pool.waker(), self.metrics.clone());
+
+        while !self.control.shutdown_requested() {
+            if let Some(new_config) = self.control.take_reload() {
+                self.reload(new_config);
+            }
+            if let Some((min_threads, max_threads)) = self.control.take_resize() {
+                self.config.min_threads = min_threads;
+                self.config.max_threads = max_threads;
+                self.fit_pool();
+            }
+
+            while let Some(worker_output) = self.pool.try_recv() {
+                self.finish(worker_output);
+            }
+
+            let stopped = self.pool.shrink_idle(
+                self.config.min_threads,
+                time::Duration::from_secs(self.config.scale_down_after));
+            if stopped > 0 {
+                log::info("scaled down").field("workers", self.pool.size()).emit();
+            }
 
-                    thread::sleep(time::Duration::from_millis(100));
+            self.next_due = self.release_due();
+            if self.pool.free_slots() > 0 || self.pool.size() < self.config.max_threads {
+                self.claim();
+            }
+            if time::Instant::now() >= self.next_heartbeat {
+                self.heartbeat();
+            }
+            if time::Instant::now() >= self.next_prune {
+                self.prune_finished();
+                self.next_prune = time::Instant::now() + time::Duration::from_secs(PRUNE_INTERVAL);
+            }
+            self.update_worker_metrics();
+            self.health.consumer_ticked(self.pool.dead_workers());
+
+            // sleep until a job finishes or the producer signals new
+            // work, rescanning once in a while in case a signal was lost,
+            // or sooner when the rate limit held tasks back or a delayed
+            // task is due
+            let mut timeout = time::Duration::from_secs(RESCAN_INTERVAL);
+            for wait in self.throttled_for.iter().chain(self.next_due.iter()) {
+                timeout = timeout.min(*wait);
+            }
+
+            if let Some(worker_output) = self.pool.recv_timeout(timeout) {
+                self.finish(worker_output);
+            }
+        }
+
+        self.drain();
+        if let Err(error) = registry::deregister(&self.redis_conn, &self.keys, &self.registration.id) {
+            log::warn("failed to deregister consumer").field("error", format!("{:?}", error)).emit();
+        }
+        log::info("consumer stopped").emit();
+    }
+
+    /// Hands as many pending tasks to the pool as it has room for, growing it
+    /// to fit the backlog.
+    fn claim(&mut self) {
+        self.throttled_for = None;
+        if let Ok(paused) = self.redis_conn.exists(self.keys.paused.as_str()) {
+            if paused != self.paused {
+                log::info(match paused {
+                    true => "consumer paused, not claiming new tasks",
+                    false => "consumer resumed",
+                }).emit();
+                self.paused = paused;
+            }
+            if paused {
+                return;
+            }
+        }
+
+        let (diff, backlog, in_order) = match self.waiting() {
+            Ok(waiting) => waiting,
+            Err(error) => {
+                log::error("failed to read pending tasks")
+                    .field("error", format!("{:?}", error))
+                    .emit();
+                self.reconnect_redis();
+                return;
+            }
+        };
+
+        // grow the pool to fit the backlog, up to the maximum
+        let wanted = self.config.max_threads.min(
+            self.pool.size() + backlog.saturating_sub(self.pool.free_slots()));
+        if wanted > self.pool.size() {
+            self.pool.resize(wanted);
+            log::info("scaled up").field("workers", self.pool.size()).emit();
+        }
+
+        let mut candidates: Vec<Candidate> = diff.into_iter()
+            .filter_map(|key| self.candidate(key))
+            .collect();
+        let ordered = self.config.fifo || self.config.concurrency_key.is_some() || self.config.priority_key.is_some();
+        if ordered && !in_order {
+            self.sort_by_arrival(&mut candidates);
+        }
+        if self.config.concurrency_key.is_some() {
+            first_per_concurrency_value(&mut candidates);
+        }
+        if self.config.priority_key.is_some() {
+            self.sort_by_priority(&mut candidates);
+        }
+
+        for task in candidates {
+            if self.pool.free_slots() == 0 { break; }
+            if let Some(ref value) = task.concurrency_value {
+                if self.concurrency_held(value).unwrap_or(true) {
+                    log::debug("task waits for its concurrency key").field("task", payload::task_id(&task.key)).emit();
+                    continue;
                 }
-            });
+            }
+            if !self.rate_allows() { break; }
 
-            return handler;
+            let claimed = self.claim_task(&task.key, task.concurrency_value.as_deref()).unwrap_or(false);
+            if !claimed {
+                // the token goes to the next task instead
+                self.rate_refund();
+                continue;
+            }
+            // expiring with the registration should this consumer die before
+            // its next heartbeat
+            let in_flight = self.keys.consumer_in_flight(&self.registration.id);
+            let _ : Result<(), _> = redis::pipe()
+                .sadd(&in_flight, &task.key).ignore()
+                .expire(&in_flight, registry::HEARTBEAT_TTL).ignore()
+                .query(&self.redis_conn);
+            let attempt: u64 = self.redis_conn.hincr(self.keys.attempts.as_str(), &task.key, 1).unwrap_or(1);
+            let mut event = log::info("start processing task")
+                .field("task", payload::task_id(&task.key))
+                .field("attempt", attempt);
+            if let Some(payload) = self.config.redactor.loggable(&task.payload) {
+                event = event.field("payload", payload);
+            }
+            event.emit();
+            self.in_flight.insert(task.key);
+            self.metrics.tasks_started.inc();
+            let _ = self.pool.execute(task.payload, attempt);
         }
     }
 
-    pub fn start_producer(&self, pg_conn: postgres::Connection, redis_client: redis::Client) -> thread::JoinHandle<()> {
-        {
-            let config = self.config.clone();
-            let pending_set = format!("dispatcher:{}:pending_set", &config.db_channel);
-            if let Err(_) = pg_conn.execute(&format!("LISTEN {}", config.db_channel), &[]) {
-                eprintln!("Failed to execute LISTEN command in database.");
-                exit(1)
-            }
-
-            let handler = thread::spawn(move||{
-                println!(
-                    "[pg-dispatcher-producer] Producer Listening to channel: \"{}\".",
-                    config.db_channel
-                    );
-
-                let notifications = pg_conn.notifications();
-                let mut iter = notifications.blocking_iter();
-
-                loop {
-                    match iter.next() {
-
-                        Ok(Some(notification)) => {
-                            let key_value = base64::encode(&notification.payload);
-                            println!("[pg-dispatcher-producer] found new notification {:?}", &key_value);
-                            let redis_conn = redis_client.get_connection().unwrap();
-                            match redis_conn.sadd(pending_set.clone(), &key_value) {
-                                Ok(1) => {
-                                    println!("[pg-dispatcher-producer] received key {}", &key_value);
-                                },
-                                Err(error) => {
-                                    println!("[pg-dispatcher-producer] error {:?}", error);
-                                },
-                                _ => {
-                                    println!("[pg-dispatcher-producer] key {} already persisted", &key_value);
-                                }
-                            };
-                        },
-                        _ => {}
-                    }
+    /// Keys of the pending tasks not running yet, how many there are and
+    /// whether the keys are in arrival order. In plain FIFO order only the
+    /// oldest `ARRIVAL_WINDOW` arrivals are read, unless fewer tasks than
+    /// waiting have one, as when queued before arrivals were recorded.
+    fn waiting(&self) -> redis::RedisResult<(Vec<String>, usize, bool)> {
+        if self.config.fifo && self.config.concurrency_key.is_none() && self.config.priority_key.is_none() {
+            let (pending, processing, oldest): (usize, HashSet<String>, Vec<String>) = redis::pipe()
+                .scard(&self.keys.pending_set)
+                .smembers(&self.keys.processing_set)
+                .zrange(&self.keys.arrival, 0, ARRIVAL_WINDOW as isize - 1)
+                .query(&self.redis_conn)?;
+            let mut is_pending = redis::pipe();
+            for key in &oldest {
+                is_pending.sismember(&self.keys.pending_set, key);
+            }
+            // arrivals are kept for the tasks waiting to be retried too
+            let flags: Vec<bool> = match oldest.is_empty() {
+                true => Vec::new(),
+                false => is_pending.query(&self.redis_conn)?,
+            };
+            let window_full = oldest.len() == ARRIVAL_WINDOW;
+            let keys: Vec<String> = oldest.into_iter().zip(flags)
+                .filter(|(key, pending)| *pending && !processing.contains(key))
+                .map(|(key, _)| key)
+                .collect();
+            let backlog = pending.saturating_sub(processing.len());
+            if window_full || keys.len() >= backlog {
+                return Ok((keys, backlog, true));
+            }
+        }
+
+        let diff: Vec<String> = self.redis_conn
+            .sdiff(&[self.keys.pending_set.clone(), self.keys.processing_set.clone()])?;
+        let backlog = diff.len();
+        Ok((diff, backlog, false))
+    }
+
+    /// Moves the delayed tasks that are due to the pending set, as if they
+    /// arrived when due, retried tasks keeping their place in line. Returns
+    /// how long until the next one is due.
+    fn release_due(&mut self) -> Option<time::Duration> {
+        let due: Vec<(String, u64)> = self.redis_conn.zrangebyscore_limit_withscores(
+            self.keys.delayed.as_str(), "-inf", unix_time(), 0, PRUNE_BATCH as isize).unwrap_or_default();
+
+        let mut released = 0;
+        for (key, _) in due {
+            if let Ok(true) = self.release_task(&key) {
+                let arrived: Option<u64> = self.redis_conn.zscore(self.keys.arrival.as_str(), &key).unwrap_or(None);
+                if arrived.is_none() {
+                    let _ : Result<(), _> = next_arrival(&self.redis_conn, &self.keys)
+                        .and_then(|arrival| self.redis_conn.zadd(self.keys.arrival.as_str(), &key, arrival));
+                }
+                released += 1;
+            }
+        }
+        if released > 0 {
+            log::info("released delayed tasks").field("tasks", released).emit();
+            let _ : Result<(), _> = self.redis_conn.publish(self.keys.wakeup_channel.as_str(), 1);
+        }
+
+        let next: Vec<(String, u64)> = self.redis_conn.zrange_withscores(self.keys.delayed.as_str(), 0, 0)
+            .unwrap_or_default();
+        next.first().map(|&(_, run_at)| {
+            (time::UNIX_EPOCH + time::Duration::from_secs(run_at))
+                .duration_since(time::SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Moves a due task from the delayed set to the pending set, unless
+    /// another consumer released it first, even if it ran since.
+    fn release_task(&self, key: &str) -> redis::RedisResult<bool> {
+        let watched = [&self.keys.delayed, &self.keys.pending_set];
+        let mut released = false;
+
+        let _ : () = redis::transaction(&self.redis_conn, &watched, |pipe| {
+            released = false;
+            let run_at: Option<u64> = self.redis_conn.zscore(self.keys.delayed.as_str(), key)?;
+            let run_at = match run_at {
+                Some(run_at) if run_at <= unix_time() => run_at,
+                _ => return Ok(Some(())),
+            };
+            pipe.zrem(&self.keys.delayed, key).ignore();
+            // a task that arrived again in the meantime keeps its enqueue time
+            let pending: bool = self.redis_conn.sismember(self.keys.pending_set.as_str(), key)?;
+            if !pending {
+                pipe.sadd(&self.keys.pending_set, key).ignore()
+                    .zadd(&self.keys.enqueued_at, key, run_at).ignore();
+                released = true;
+            }
+            pipe.query(&self.redis_conn)
+        })?;
+
+        Ok(released)
+    }
+
+    /// A pending task as considered for claiming, `None` if its key does
+    /// not decode to a UTF-8 payload.
+    fn candidate(&self, key: String) -> Option<Candidate> {
+        let payload = base64::decode(&key).ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())?;
+        let concurrency_value = self.config.concurrency_key.as_ref()
+            .and_then(|path| payload::value_at(&payload, path));
+        let priority = self.config.priority_key.as_ref()
+            .and_then(|path| payload::value_at(&payload, path))
+            .and_then(|priority| priority.parse::<i64>().ok())
+            .unwrap_or(self.config.priority);
+
+        Some(Candidate { key, payload, concurrency_value, priority })
+    }
+
+    /// Orders tasks by arrival, those that arrived before arrivals were
+    /// recorded first.
+    fn sort_by_arrival(&self, tasks: &mut [Candidate]) {
+        let arrival = self.scores(&self.keys.arrival, tasks);
+        tasks.sort_by_key(|task| arrival.get(&task.key).cloned().unwrap_or(0));
+    }
+
+    /// Orders tasks by priority, highest first, counting the time they
+    /// waited in so that low priorities do not starve.
+    fn sort_by_priority(&self, tasks: &mut [Candidate]) {
+        let now = unix_time();
+        let enqueued_at = self.scores(&self.keys.enqueued_at, tasks);
+        tasks.sort_by_key(|task| {
+            let waited = enqueued_at.get(&task.key).map_or(0, |enqueued_at| now.saturating_sub(*enqueued_at));
+            Reverse(effective_priority(task.priority, waited, self.config.priority_aging))
+        });
+    }
+
+    /// Scores of the tasks in a sorted set, read in one round trip rather
+    /// than with the whole set.
+    fn scores(&self, set: &str, tasks: &[Candidate]) -> HashMap<String, u64> {
+        if tasks.is_empty() {
+            return HashMap::new();
+        }
+        let mut pipe = redis::pipe();
+        for task in tasks {
+            pipe.zscore(set, &task.key);
+        }
+        let scores: Vec<Option<u64>> = pipe.query(&self.redis_conn).unwrap_or_default();
+        tasks.iter().zip(scores)
+            .filter_map(|(task, score)| score.map(|score| (task.key.clone(), score)))
+            .collect()
+    }
+
+    /// Whether a running task, or one waiting to be retried, holds the
+    /// concurrency key `value`.
+    fn concurrency_held(&self, value: &str) -> redis::RedisResult<bool> {
+        let holder: Option<String> = self.redis_conn.hget(self.keys.concurrency.as_str(), value)?;
+        let holder = match holder {
+            Some(holder) => holder,
+            None => return Ok(false),
+        };
+        let retrying: Option<u64> = self.redis_conn.zscore(self.keys.delayed.as_str(), holder.as_str())?;
+        Ok(retrying.is_some() || self.redis_conn.sismember(self.keys.processing_set.as_str(), holder)?)
+    }
+
+    /// Claims a task still pending, along with its concurrency key if any,
+    /// unless another consumer claimed either first or the task was cancelled
+    /// or deleted meanwhile.
+    fn claim_task(&self, key: &str, concurrency_value: Option<&str>) -> redis::RedisResult<bool> {
+        let watched = [&self.keys.pending_set, &self.keys.processing_set, &self.keys.concurrency, &self.keys.delayed];
+        let mut claimed = false;
+
+        let _ : () = redis::transaction(&self.redis_conn, &watched, |pipe| {
+            let pending: bool = self.redis_conn.sismember(self.keys.pending_set.as_str(), key)?;
+            let processing: bool = self.redis_conn.sismember(self.keys.processing_set.as_str(), key)?;
+            claimed = pending && !processing && match concurrency_value {
+                Some(value) => !self.concurrency_held(value)?,
+                None => true,
+            };
+            if !claimed {
+                return Ok(Some(()));
+            }
+            if let Some(value) = concurrency_value {
+                pipe.hset(&self.keys.concurrency, value, key).ignore();
+            }
+            pipe.sadd(&self.keys.processing_set, key).ignore()
+                .query(&self.redis_conn)
+        })?;
+
+        Ok(claimed)
+    }
+
+    /// Lets the next task with the same concurrency key run, while the task
+    /// still holds the key as a member of the processing set.
+    fn release_concurrency_key(&self, key: &str) {
+        let value = match self.config.concurrency_key {
+            Some(ref path) => base64::decode(key).ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|payload| payload::value_at(&payload, path)),
+            None => None,
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return,
+        };
+
+        let holder: Option<String> = self.redis_conn.hget(self.keys.concurrency.as_str(), value.as_str())
+            .unwrap_or(None);
+        if holder.as_deref() == Some(key) {
+            let _ : Result<(), _> = self.redis_conn.hdel(self.keys.concurrency.as_str(), value.as_str());
+            let _ : Result<(), _> = self.redis_conn.publish(self.keys.wakeup_channel.as_str(), 1);
+        }
+    }
+
+    /// Whether the rate limit lets another task start, taking a token if so.
+    fn rate_allows(&mut self) -> bool {
+        let limiter = match self.rate_limiter {
+            Some(ref mut limiter) => limiter,
+            None => return true,
+        };
+
+        match limiter.try_acquire(&self.redis_conn) {
+            Ok(true) => true,
+            Ok(false) => {
+                log::debug("rate limit reached").field("wait", log::seconds(limiter.wait())).emit();
+                self.metrics.rate_limited.inc();
+                self.throttled_for = Some(limiter.wait());
+                false
+            },
+            Err(error) => {
+                // hold tasks back rather than exceed a shared quota
+                log::error("failed to take a token from the shared rate limit")
+                    .field("error", format!("{:?}", error))
+                    .emit();
+                self.throttled_for = Some(time::Duration::from_secs(1));
+                false
+            },
+        }
+    }
+
+    /// Gives back the token `rate_allows` took for a task another consumer
+    /// claimed first.
+    fn rate_refund(&mut self) {
+        if let Some(ref mut limiter) = self.rate_limiter {
+            if let Err(error) = limiter.release(&self.redis_conn) {
+                log::warn("failed to give a token back to the shared rate limit")
+                    .field("error", format!("{:?}", error))
+                    .emit();
+            }
+        }
+    }
+
+    /// Records the outcome of a task reported back by the pool.
+    fn finish(&mut self, worker_output: WorkerMessage) {
+        self.in_flight.remove(worker_output.key());
+        let _ : Result<(), _> = self.redis_conn
+            .srem(self.keys.consumer_in_flight(&self.registration.id), worker_output.key());
+
+        match worker_output {
+            WorkerMessage::Aborted(b64_key) => {
+                self.release_concurrency_key(&b64_key);
+                self.metrics.tasks_retried.inc();
+                let _ : Result<(),_> = self.redis_conn.
+                    srem(self.keys.processing_set.clone(), b64_key);
+            },
+            WorkerMessage::Finished(b64_key, outcome @ Outcome::Retryable(_), duration)
+                | WorkerMessage::Finished(b64_key, outcome @ Outcome::Timeout(_), duration)
+                if self.attempts_exhausted(&b64_key) => {
+                log::warn("giving up on task")
+                    .field("task", payload::task_id(&b64_key))
+                    .field("attempts", self.config.max_attempts)
+                    .emit();
+                let output = outcome.output();
+                self.metrics.observe_command(output.code.as_deref(), duration);
+                let mut result = output.details.clone();
+                result["duration"] = json!(log::seconds(duration));
+                result["attempts"] = json!(self.config.max_attempts);
+                self.release_concurrency_key(&b64_key);
+                self.complete(b64_key, false, result);
+            },
+            WorkerMessage::Finished(b64_key, Outcome::Retryable(_), _)
+                | WorkerMessage::Finished(b64_key, Outcome::Timeout(_), _) => {
+                // the task keeps its concurrency key until it runs again
+                self.metrics.tasks_retried.inc();
+                self.retry_later(&b64_key);
+            },
+            WorkerMessage::Finished(b64_key, outcome, duration) => {
+                let success = matches!(outcome, Outcome::Success(_));
+                let output = outcome.output();
+                // status codes and SQLSTATEs are counted along with exit codes
+                self.metrics.observe_command(output.code.as_deref(), duration);
+                let mut result = output.details.clone();
+                result["duration"] = json!(log::seconds(duration));
+                self.release_concurrency_key(&b64_key);
+                self.complete(b64_key, success, result);
+            },
+        }
+    }
+
+    /// Whether a task ran `--max-attempts` times, and is not to be retried.
+    fn attempts_exhausted(&self, b64_key: &str) -> bool {
+        let attempt: Option<u64> = self.redis_conn.hget(self.keys.attempts.as_str(), b64_key).unwrap_or(None);
+        self.config.max_attempts > 0 && attempt.unwrap_or(0) >= self.config.max_attempts
+    }
+
+    /// Records a task as done or dead, with the result of its run.
+    fn complete(&mut self, b64_key: String, success: bool, mut result: serde_json::Value) {
+        match success {
+            true => self.metrics.tasks_succeeded.inc(),
+            false => self.metrics.tasks_failed.inc(),
+        }
+
+        // failed tasks are kept apart for inspection, both only if they are
+        // to be remembered at all
+        let finished_at = unix_time();
+        let (set, max_entries) = match success {
+            true => (&self.keys.done, self.config.done_max_entries),
+            false => (&self.keys.dead, self.config.dead_max_entries),
+        };
+        let recorded = max_entries != Some(0) && self.redis_conn
+            .zadd::<_, _, _, ()>(set.as_str(), b64_key.clone(), finished_at)
+            .is_ok();
+
+        // counted for the throughput of `pg-dispatcher status`
+        let bucket = self.keys.finished_bucket(finished_at);
+        let outcome = match success {
+            true => "done",
+            false => "dead",
+        };
+        let _ : Result<(), _> = self.redis_conn.hincr(bucket.as_str(), outcome, 1);
+        let _ : Result<(), _> = self.redis_conn.expire(bucket.as_str(), 120);
+
+        if recorded {
+            result["finished_at"] = json!(finished_at);
+            let _ : Result<(), _> = self.redis_conn.
+                hset(self.keys.results.clone(), b64_key.clone(), result.to_string());
+        }
+
+        // remove from pending set
+        let _ : Result<(),_> = self.redis_conn.
+            srem(self.keys.pending_set.clone(), b64_key.clone());
+        let _ : Result<(),_> = self.redis_conn.
+            zrem(self.keys.enqueued_at.clone(), b64_key.clone());
+        let _ : Result<(),_> = self.redis_conn.
+            zrem(self.keys.arrival.clone(), b64_key.clone());
+        let _ : Result<(),_> = self.redis_conn.
+            hdel(self.keys.attempts.clone(), b64_key.clone());
+
+        // remove from processing set
+        let _ : Result<(),_> = self.redis_conn.
+            srem(self.keys.processing_set.clone(), b64_key.clone());
+    }
+
+    /// Moves a task back to the delayed set, to be claimed again after the
+    /// retry delay in its place in line.
+    fn retry_later(&mut self, b64_key: &str) {
+        let run_at = unix_time() + self.config.retry_delay;
+        let moved: redis::RedisResult<()> = redis::pipe().atomic()
+            .srem(&self.keys.processing_set, b64_key).ignore()
+            .srem(&self.keys.pending_set, b64_key).ignore()
+            .zrem(&self.keys.enqueued_at, b64_key).ignore()
+            .zadd(&self.keys.delayed, b64_key, run_at).ignore()
+            .query(&self.redis_conn);
+        let attempt: Option<u64> = self.redis_conn.hget(self.keys.attempts.as_str(), b64_key).unwrap_or(None);
+        match moved {
+            Ok(()) => log::info("retrying task later")
+                .field("task", payload::task_id(b64_key))
+                .field("attempt", attempt)
+                .field("run_at", run_at)
+                .emit(),
+            // the task stays pending to be claimed again right away
+            Err(error) => {
+                log::error("failed to delay task")
+                    .field("task", payload::task_id(b64_key))
+                    .field("error", format!("{:?}", error))
+                    .emit();
+                let _ : Result<(), _> = self.redis_conn.srem(self.keys.processing_set.as_str(), b64_key);
+            },
+        }
+    }
+
+    /// Applies the reloadable parts of a new configuration.
+    fn reload(&mut self, new_config: Config) {
+        let current = self.config.clone();
+
+        if new_config.db_channel != current.db_channel
+            || new_config.redis_url != current.redis_url
+            || new_config.consumer != current.consumer {
+            log::warn("channel, Redis and mode changes need a restart, ignoring them").emit();
+        }
+
+        if new_config.command_vector != current.command_vector
+            || new_config.webhook != current.webhook
+            || new_config.sql != current.sql
+            || new_config.persistent != current.persistent
+            || new_config.routes != current.routes {
+            log::info("executing new commands for new tasks")
+                .field("exec", format!("{:?}", new_config.command_vector))
+                .field("routes", new_config.routes.len())
+                .emit();
+            self.pool.set_routes(routes(&new_config));
+        }
+
+        if new_config.rate_limit != current.rate_limit
+            || new_config.rate_limit_shared != current.rate_limit_shared {
+            log::info("changing the rate limit")
+                .field("rate_limit", new_config.rate_limit.map(|rate| rate.to_string()))
+                .emit();
+            self.rate_limiter = rate_limiter(&new_config, &self.keys);
+        }
+
+        self.config = Config {
+            db_url: current.db_url,
+            redis_url: current.redis_url,
+            db_channel: current.db_channel,
+            consumer: current.consumer,
+            producer: current.producer,
+            tls_mode: current.tls_mode,
+            ..new_config
+        };
+        self.fit_pool();
+    }
+
+    /// Resizes the pool into the configured worker range.
+    fn fit_pool(&mut self) {
+        let size = self.pool.size().max(self.config.min_threads).min(self.config.max_threads);
+        if size != self.pool.size() {
+            log::info("resizing pool")
+                .field("from", self.pool.size())
+                .field("workers", size)
+                .emit();
+            self.pool.resize(size);
+        }
+    }
+
+    /// Waits up to the drain timeout for the tasks still running, then kills
+    /// what is left and hands their keys back to the pending set.
+    fn drain(&mut self) {
+        let drain_timeout = time::Duration::from_secs(self.config.drain_timeout);
+        log::info("draining running tasks")
+            .field("tasks", self.in_flight.len())
+            .field("timeout", drain_timeout.as_secs())
+            .emit();
+
+        let mut deadline = time::Instant::now() + drain_timeout;
+        let mut aborted = false;
+
+        while !self.in_flight.is_empty() {
+            let now = time::Instant::now();
+            if now >= deadline {
+                if aborted { break; }
+                log::warn("drain timeout reached, aborting tasks")
+                    .field("tasks", self.in_flight.len())
+                    .emit();
+                self.pool.abort();
+                aborted = true;
+                deadline = now + time::Duration::from_secs(ABORT_TIMEOUT);
+                continue;
+            }
+
+            if let Some(worker_output) = self.pool.recv_timeout(deadline - now) {
+                self.finish(worker_output);
+            }
+        }
+
+        for key in self.in_flight.drain() {
+            let _ : Result<(),_> = self.redis_conn.srem(self.keys.processing_set.clone(), key);
+        }
+    }
+
+    /// Forgets the done and dead tasks past their TTL or maximum number of
+    /// entries, along with their results.
+    fn prune_finished(&mut self) {
+        let done = (self.keys.done.clone(), self.config.done_ttl, self.config.done_max_entries);
+        let dead = (self.keys.dead.clone(), self.config.dead_ttl, self.config.dead_max_entries);
+        for (state, (set, ttl, max_entries)) in [("done", done), ("dead", dead)] {
+            let pruned = self.prune(&set, ttl, max_entries);
+            if pruned > 0 {
+                log::info(format!("pruned {} tasks", state)).field("tasks", pruned).emit();
+            }
+        }
+    }
+
+    /// Forgets the tasks of a finished set older than `ttl` seconds or beyond
+    /// the `max_entries` most recent ones, returning how many.
+    fn prune(&mut self, set: &str, ttl: Option<u64>, max_entries: Option<usize>) -> usize {
+        let mut pruned = 0;
+
+        loop {
+            let mut batch: Vec<String> = Vec::new();
+            if let Some(ttl) = ttl {
+                batch = self.redis_conn.zrangebyscore_limit(
+                    set, "-inf", unix_time().saturating_sub(ttl),
+                    0, PRUNE_BATCH as isize).unwrap_or_default();
+            }
+            if let (true, Some(max_entries)) = (batch.is_empty(), max_entries) {
+                let size: usize = self.redis_conn.zcard(set).unwrap_or(0);
+                let excess = size.saturating_sub(max_entries).min(PRUNE_BATCH);
+                if excess > 0 {
+                    // oldest first
+                    batch = self.redis_conn.zrange(set, 0, excess as isize - 1)
+                        .unwrap_or_default();
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+
+            let removed: redis::RedisResult<()> = self.redis_conn.zrem(set, &batch[..])
+                .and_then(|()| self.redis_conn.hdel(self.keys.results.as_str(), &batch[..]));
+            if let Err(error) = removed {
+                log::error("failed to prune finished tasks").field("error", format!("{:?}", error)).emit();
+                break;
+            }
+            pruned += batch.len();
+        }
+        pruned
+    }
+
+    /// Moves the keys of the set earlier versions kept done tasks in to
+    /// `done`, as if they had just finished.
+    fn migrate_done_set(&mut self) {
+        let keys: Vec<String> = match self.redis_conn.smembers(self.keys.legacy_done_set.as_str()) {
+            Ok(keys) => keys,
+            Err(_) => return,
+        };
+        if keys.is_empty() {
+            return;
+        }
+
+        if self.config.done_max_entries != Some(0) {
+            let finished_at = unix_time();
+            for chunk in keys.chunks(PRUNE_BATCH) {
+                let items: Vec<(u64, &String)> = chunk.iter().map(|key| (finished_at, key)).collect();
+                let _ : Result<(), _> = self.redis_conn.zadd_multiple(self.keys.done.as_str(), &items);
+            }
+        }
+        let _ : Result<(), _> = self.redis_conn.del(self.keys.legacy_done_set.as_str());
+        log::info("migrated done tasks to a sorted set").field("tasks", keys.len()).emit();
+    }
+
+    /// Refreshes the registration of the consumer in Redis.
+    fn heartbeat(&mut self) {
+        self.registration.workers = self.pool.size();
+        self.registration.heartbeat_at = unix_time();
+        if let Err(error) = registry::heartbeat(&self.redis_conn, &self.keys, &self.registration) {
+            log::warn("failed to send heartbeat").field("error", format!("{:?}", error)).emit();
+        }
+        self.next_heartbeat = time::Instant::now() + time::Duration::from_secs(registry::HEARTBEAT_INTERVAL);
+    }
+
+    fn reconnect_redis(&mut self) {
+        match self.redis_client.get_connection() {
+            Ok(redis_conn) => {
+                self.redis_conn = redis_conn;
+                self.metrics.redis_reconnects.inc();
+            },
+            Err(error) => {
+                log::error("failed to reconnect to Redis")
+                    .field("error", format!("{:?}", error))
+                    .emit();
+            }
+        }
+    }
+
+    fn update_worker_metrics(&self) {
+        self.metrics.idle_workers.set(self.pool.free_slots());
+        self.metrics.busy_workers.set(self.pool.busy_slots());
+    }
+}
+
+/// A pending task considered for claiming.
+struct Candidate {
+    key: String,
+    payload: String,
+    concurrency_value: Option<String>,
+    priority: i64,
+}
+
+/// Keeps the first of the tasks sharing a concurrency key value, the later
+/// ones waiting for it to finish.
+fn first_per_concurrency_value(candidates: &mut Vec<Candidate>) {
+    let mut seen = HashSet::new();
+    candidates.retain(|task| match task.concurrency_value {
+        Some(ref value) => seen.insert(value.clone()),
+        None => true,
+    });
+}
+
+/// The priority of a task that waited `waited` seconds, raised a level for
+/// every `aging` seconds.
+fn effective_priority(priority: i64, waited: u64, aging: u64) -> i64 {
+    match aging {
+        0 => priority,
+        aging => priority.saturating_add((waited / aging) as i64),
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.health.consumer_stopped();
+    }
+}
+
+/// Moves notifications of a channel into its pending set.
+struct Producer {
+    config: Config,
+    keys: RedisKeys,
+    redis_client: redis::Client,
+    /// Dropped when it fails, to reconnect on the next notification.
+    redis_conn: Option<redis::Connection>,
+    control: Arc<Control>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+}
+
+impl Producer {
+    fn new(dispatcher: &Dispatcher, redis_client: redis::Client) -> Producer {
+        Producer {
+            config: dispatcher.config.clone(),
+            keys: RedisKeys::for_channel(&dispatcher.config.db_channel),
+            redis_conn: redis_client.get_connection().ok(),
+            redis_client,
+            control: dispatcher.control.clone(),
+            metrics: dispatcher.metrics.clone(),
+            health: dispatcher.health.clone(),
+        }
+    }
+
+    fn run(mut self, mut pg_conn: postgres::Connection) {
+        log::info("producer listening").emit();
+
+        while let Err(error) = self.listen(&pg_conn) {
+            log::error("lost connection to the database").field("error", error.to_string()).emit();
+            self.health.set_listening(false);
+            match self.reconnect_postgres() {
+                Some(new_conn) => pg_conn = new_conn,
+                None => return,
+            }
+        }
+
+        if let Err(error) = pg_conn.execute(&format!("UNLISTEN {}", self.config.db_channel), &[]) {
+            log::warn("failed to execute UNLISTEN command").field("error", error.to_string()).emit();
+        }
+        log::info("producer stopped listening").emit();
+    }
+
+    /// Enqueues notifications until shutdown is requested.
+    fn listen(&mut self, pg_conn: &postgres::Connection) -> postgres::Result<()> {
+        let notifications = pg_conn.notifications();
+        // wake up every second to notice shutdown requests
+        let mut iter = notifications.timeout_iter(time::Duration::from_secs(1));
+
+        while !self.control.shutdown_requested() {
+            if let Some(new_config) = self.control.take_producer_reload() {
+                self.reload(new_config);
+            }
+            if let Some(notification) = iter.next()? {
+                self.enqueue(&notification.payload);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the parts of a new configuration the producer reads, for the
+    /// notifications received from then on.
+    fn reload(&mut self, new_config: Config) {
+        if new_config.filters != self.config.filters {
+            log::info("filtering notifications anew").field("filters", new_config.filters.len()).emit();
+        }
+        self.config.filters = new_config.filters;
+        self.config.run_at_key = new_config.run_at_key;
+        self.config.redactor = new_config.redactor;
+    }
+
+    fn enqueue(&mut self, payload: &str) {
+        self.metrics.notifications_received.inc();
+        let key_value = base64::encode(payload);
+        log::debug("found new notification").field("task", payload::task_id(&key_value)).emit();
+
+        if !self.config.filters.iter().all(|filter| filter.matches(payload)) {
+            self.metrics.notifications_filtered.inc();
+            log::debug("filtered out notification").field("task", payload::task_id(&key_value)).emit();
+            return;
+        }
+
+        // tasks due later wait in the delayed set
+        let run_at = self.config.run_at_key.as_ref()
+            .and_then(|path| payload::value_at(payload, path))
+            .and_then(|run_at| {
+                let parsed = payload::parse_timestamp(&run_at);
+                if parsed.is_none() {
+                    log::warn("invalid run_at timestamp, running the task now")
+                        .field("task", payload::task_id(&key_value))
+                        .field("run_at", run_at)
+                        .emit();
                 }
+                parsed
+            })
+            .filter(|run_at| *run_at > unix_time());
+
+        let mut added = self.add(&key_value, run_at);
+        if added.is_err() {
+            // the connection may have broken since the last notification,
+            // try once more on a new one
+            self.redis_conn = None;
+            added = self.add(&key_value, run_at);
+        }
+        match added {
+            Ok(true) => {
+                let mut event = match run_at {
+                    Some(run_at) => log::info("scheduled task").field("run_at", run_at),
+                    None => log::info("received task"),
+                }.field("task", payload::task_id(&key_value));
+                if let Some(payload) = self.config.redactor.loggable(payload) {
+                    event = event.field("payload", payload);
+                }
+                event.emit();
+            },
+            Ok(false) => {
+                log::info("task already persisted").field("task", payload::task_id(&key_value)).emit();
+            },
+            Err(error) => {
+                log::error("failed to enqueue task")
+                    .field("task", payload::task_id(&key_value))
+                    .field("error", format!("{:?}", error))
+                    .emit();
+                self.redis_conn = None;
+            }
+        };
+    }
+
+    /// Adds a task to the pending set, or to the delayed one when due later,
+    /// returning whether it was new. A task already waiting in either is not
+    /// added again, as it would run twice.
+    fn add(&mut self, key_value: &str, run_at: Option<u64>) -> redis::RedisResult<bool> {
+        if self.redis_conn.is_none() {
+            self.redis_conn = Some(self.redis_client.get_connection()?);
+            self.metrics.redis_reconnects.inc();
+        }
+        let redis_conn = self.redis_conn.as_ref().unwrap();
+        let keys = &self.keys;
+        let arrival = match run_at {
+            Some(_) => None,
+            None => Some(next_arrival(redis_conn, keys)?),
+        };
+
+        let mut added = false;
+        let _ : () = redis::transaction(redis_conn, &[&keys.pending_set, &keys.delayed], |pipe| {
+            let pending: bool = redis_conn.sismember(keys.pending_set.as_str(), key_value)?;
+            let delayed: Option<u64> = redis_conn.zscore(keys.delayed.as_str(), key_value)?;
+            added = !pending && delayed.is_none();
+            if !added {
+                return Ok(Some(()));
+            }
+            match (run_at, arrival) {
+                (Some(run_at), _) => pipe.zadd(&keys.delayed, key_value, run_at).ignore(),
+                (None, arrival) => pipe.sadd(&keys.pending_set, key_value).ignore()
+                    .zadd(&keys.enqueued_at, key_value, unix_time()).ignore()
+                    .zadd(&keys.arrival, key_value, arrival.unwrap_or(0)).ignore(),
+            };
+            pipe.query(redis_conn)
+        })?;
+        if !added {
+            return Ok(false);
+        }
+
+        // consumers also wake up to wait for a delayed task
+        let _ : Result<(), _> = redis_conn
+            .publish(keys.wakeup_channel.clone(), 1);
+        Ok(true)
+    }
+
+    /// Connects and listens again, retrying every second until shutdown.
+    fn reconnect_postgres(&self) -> Option<postgres::Connection> {
+        while !self.control.shutdown_requested() {
+            thread::sleep(time::Duration::from_secs(1));
+
+            let listening = connect_postgres(&self.config).and_then(|pg_conn| {
+                pg_conn.execute(&format!("LISTEN {}", self.config.db_channel), &[])?;
+                Ok(pg_conn)
             });
 
-            return handler;
+            match listening {
+                Ok(pg_conn) => {
+                    log::info("reconnected to the database").emit();
+                    self.metrics.postgres_reconnects.inc();
+                    self.health.set_listening(true);
+                    return Some(pg_conn);
+                },
+                Err(error) => {
+                    log::error("failed to reconnect to the database")
+                        .field("error", error.to_string())
+                        .emit();
+                }
+            }
         }
+        None
     }
 }
 
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.health.producer_stopped();
+    }
+}
+
+fn rate_limiter(config: &Config, keys: &RedisKeys) -> Option<RateLimiter> {
+    config.rate_limit.map(|rate| RateLimiter::new(rate, match config.rate_limit_shared {
+        true => Some(keys.rate_limit.clone()),
+        false => None,
+    }))
+}
+
+pub fn unix_time() -> u64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Wakes the consumer up whenever the producer announces new pending work.
+fn spawn_wakeup_listener(redis_client: redis::Client, wakeup_channel: String, waker: Waker,
+                         metrics: Arc<Metrics>) {
+    thread::spawn(move|| {
+        let mut subscribed_before = false;
+        loop {
+            let subscribed = redis_client.get_pubsub().and_then(|mut pubsub| {
+                pubsub.subscribe(wakeup_channel.as_str())?;
+                Ok(pubsub)
+            });
+
+            match subscribed {
+                Ok(pubsub) => {
+                    if subscribed_before {
+                        metrics.redis_reconnects.inc();
+                    }
+                    subscribed_before = true;
+                    // pick up anything published before the subscription
+                    waker.wake();
+                    while pubsub.get_message().is_ok() {
+                        waker.wake();
+                    }
+                },
+                Err(error) => {
+                    log::error("failed to subscribe to wake-ups")
+                        .field("redis_channel", wakeup_channel.as_str())
+                        .field("error", format!("{:?}", error))
+                        .emit();
+                }
+            }
+
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fake_redis::FakeRedis;
+    use executor::Output;
     use cli;
 
     #[test]
@@ -209,7 +1551,7 @@ mod tests {
                               "--exec", "sh test.sh",
                               "--workers", "5",
         ]);
-        let config = Config::from_matches(&matches);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
 
         assert_eq!(config.db_url, "foodb");
         assert_eq!(config.db_channel, "foochan");
@@ -223,6 +1565,69 @@ mod tests {
         assert_eq!(config.max_threads, 5);
     }
 
+    #[test]
+    fn dispatcher_config_drain_timeout_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--db-uri", "foodb",
+            "--redis-uri", "redis_uri",
+            "--channel", "foochan",
+            "--exec", "sh test.sh",
+        ];
+        let matches = cli::create_cli_app().get_matches_from(args.clone());
+        assert_eq!(Config::from_settings(&Settings::from_matches(&matches)).unwrap().drain_timeout, 30);
+
+        let matches = cli::create_cli_app().get_matches_from(args.into_iter().chain(vec!["--drain-timeout", "60"]));
+        assert_eq!(Config::from_settings(&Settings::from_matches(&matches)).unwrap().drain_timeout, 60);
+    }
+
+    #[test]
+    fn dispatcher_config_worker_range_test() {
+        let matches = cli::create_cli_app()
+            .get_matches_from(vec![
+                              "pg-dispatch",
+                              "--db-uri", "foodb",
+                              "--redis-uri", "redis_uri",
+                              "--channel", "foochan",
+                              "--exec", "sh test.sh",
+                              "--max-workers", "20",
+                              "--scale-down-after", "10",
+        ]);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+
+        assert_eq!(config.min_threads, 1);
+        assert_eq!(config.max_threads, 20);
+        assert_eq!(config.scale_down_after, 10);
+
+        // a fixed size pool
+        let matches = cli::create_cli_app()
+            .get_matches_from(vec![
+                              "pg-dispatch",
+                              "--db-uri", "foodb",
+                              "--redis-uri", "redis_uri",
+                              "--channel", "foochan",
+                              "--exec", "sh test.sh",
+                              "--workers", "5",
+        ]);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+        assert_eq!((config.min_threads, config.max_threads), (5, 5));
+
+        // more workers kept than allowed
+        for max_workers in &[&[][..], &["--max-workers", "6"][..]] {
+            let mut args = vec![
+                "pg-dispatch",
+                "--db-uri", "foodb",
+                "--redis-uri", "redis_uri",
+                "--channel", "foochan",
+                "--exec", "sh test.sh",
+                "--min-workers", "8",
+            ];
+            args.extend_from_slice(max_workers);
+            let matches = cli::create_cli_app().get_matches_from(args);
+            assert!(Config::from_settings(&Settings::from_matches(&matches)).unwrap_err().contains("--min-workers"));
+        }
+    }
+
     #[test]
     fn dispatcher_from_config() {
         let matches = cli::create_cli_app().get_matches_from(vec![
@@ -238,8 +1643,525 @@ mod tests {
                                                              "--workers",
                                                              "5",
         ]);
-        let config = Config::from_matches(&matches);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
 
         let _disptacher = Dispatcher::from_config(&config);
     }
+
+    #[test]
+    fn dispatcher_config_requires_exec_test() {
+        let matches = cli::create_cli_app()
+            .get_matches_from(vec![
+                              "pg-dispatch",
+                              "--db-uri", "foodb",
+                              "--redis-uri", "redis_uri",
+                              "--channel", "foochan",
+        ]);
+
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).is_err());
+    }
+
+    #[test]
+    fn dispatcher_config_done_retention_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--redis-uri", "redis_uri",
+            "--mode", "consumer",
+            "--channel", "foochan",
+            "--exec", "cat",
+            "--done-ttl", "3600",
+        ];
+        let matches = cli::create_cli_app().get_matches_from(args.clone());
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+
+        assert_eq!(config.done_ttl, Some(3600));
+        assert_eq!(config.done_max_entries, None);
+
+        let matches = cli::create_cli_app()
+            .get_matches_from(args.into_iter().chain(vec!["--done-max-entries", "many"]));
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).is_err());
+    }
+
+    #[test]
+    fn dispatcher_config_rate_limit_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--redis-uri", "redis_uri",
+            "--mode", "consumer",
+            "--channel", "foochan",
+            "--exec", "cat",
+            "--rate-limit", "50/s",
+        ];
+        let matches = cli::create_cli_app()
+            .get_matches_from(args.iter().chain(&["--rate-limit-scope", "channel"]));
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+
+        assert_eq!(config.rate_limit, Some(Rate { count: 50, period: 1 }));
+        assert!(config.rate_limit_shared);
+
+        let matches = cli::create_cli_app()
+            .get_matches_from(args.iter().chain(&["--rate-limit-scope", "everyone"]));
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).is_err());
+    }
+
+    #[test]
+    fn dispatcher_config_run_at_key_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--redis-uri", "redis_uri",
+            "--db-uri", "foodb",
+            "--channel", "foochan",
+            "--exec", "cat",
+        ];
+        let run_at_key = |extra: &[&'static str]| {
+            let matches = cli::create_cli_app().get_matches_from(args.iter().chain(extra));
+            Config::from_settings(&Settings::from_matches(&matches)).unwrap().run_at_key
+        };
+
+        assert_eq!(run_at_key(&[]), Some("$.run_at".to_string()));
+        assert_eq!(run_at_key(&["--run-at-key", "$.due"]), Some("$.due".to_string()));
+        assert_eq!(run_at_key(&["--run-at-key", ""]), None);
+    }
+
+    #[test]
+    fn dispatcher_config_order_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--redis-uri", "redis_uri",
+            "--mode", "consumer",
+            "--channel", "foochan",
+            "--exec", "cat",
+        ];
+        let fifo = |order: &[&'static str]| {
+            let matches = cli::create_cli_app().get_matches_from(args.iter().chain(order));
+            Config::from_settings(&Settings::from_matches(&matches)).map(|config| config.fifo)
+        };
+
+        assert_eq!(fifo(&[]), Ok(false));
+        assert_eq!(fifo(&["--order", "any"]), Ok(false));
+        assert_eq!(fifo(&["--order", "fifo"]), Ok(true));
+        assert!(fifo(&["--order", "lifo"]).is_err());
+    }
+
+    #[test]
+    fn dispatcher_config_priority_test() {
+        let matches = cli::create_cli_app()
+            .get_matches_from(vec![
+                              "pg-dispatch",
+                              "--redis-uri", "redis_uri",
+                              "--mode", "consumer",
+                              "--channel", "foochan",
+                              "--exec", "cat",
+                              "--priority-key", "$.priority",
+                              "--priority", "-5",
+        ]);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+
+        assert_eq!(config.priority_key, Some("$.priority".to_string()));
+        assert_eq!(config.priority, -5);
+        assert_eq!(config.priority_aging, 60);
+        assert_eq!(effective_priority(-5, 119, 60), -4);
+        assert_eq!(effective_priority(10, 3600, 0), 10);
+    }
+
+    #[test]
+    fn dispatcher_config_routes_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--db-uri", "foodb",
+            "--redis-uri", "redis_uri",
+            "--channel", "foochan",
+            "--exec", "cat",
+        ];
+        let matches = cli::create_cli_app().get_matches_from(args.clone());
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).unwrap().routes.is_empty());
+
+        let mut routed = args.clone();
+        routed.extend_from_slice(&["--route", "invoice:* => ./invoice.sh", "--route", "/^order:/ => ./order.sh"]);
+        let matches = cli::create_cli_app().get_matches_from(routed);
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+        assert_eq!(config.routes, vec![
+            Route::parse("invoice:* => ./invoice.sh").unwrap(),
+            Route::parse("/^order:/ => ./order.sh").unwrap(),
+        ]);
+
+        let mut invalid = args.clone();
+        invalid.extend_from_slice(&["--route", "invoice:*"]);
+        let matches = cli::create_cli_app().get_matches_from(invalid);
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).is_err());
+
+        // other executors would be bypassed by routed payloads
+        for executor in &[vec!["--exec", "cat", "--persistent"], vec!["--http-post", "http://localhost/"], vec!["--sql", "SELECT $1"]] {
+            let mut combined = args[..args.len() - 2].to_vec();
+            combined.extend_from_slice(executor);
+            combined.extend_from_slice(&["--route", "invoice:* => ./invoice.sh"]);
+            let matches = cli::create_cli_app().get_matches_from(combined);
+            assert!(Config::from_settings(&Settings::from_matches(&matches)).unwrap_err().contains("--route"));
+        }
+    }
+
+    #[test]
+    fn dispatcher_config_filters_test() {
+        let args = vec![
+            "pg-dispatch",
+            "--db-uri", "foodb",
+            "--redis-uri", "redis_uri",
+            "--channel", "foochan",
+            "--exec", "cat",
+        ];
+        let matches = cli::create_cli_app().get_matches_from(args.clone());
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).unwrap().filters.is_empty());
+
+        let mut args = args;
+        args.extend_from_slice(&["--filter", "$.op != DELETE", "--filter", r#"$.table in ["orders","refunds"]"#]);
+        let matches = cli::create_cli_app().get_matches_from(args.clone());
+        let config = Config::from_settings(&Settings::from_matches(&matches)).unwrap();
+
+        assert_eq!(config.filters.len(), 2);
+        assert!(config.filters.iter().all(|filter| filter.matches(r#"{"op":"UPDATE","table":"orders"}"#)));
+
+        let mut invalid = args;
+        invalid.extend_from_slice(&["--filter", "$.table in orders"]);
+        let matches = cli::create_cli_app().get_matches_from(invalid);
+        assert!(Config::from_settings(&Settings::from_matches(&matches)).is_err());
+    }
+
+    /// The configuration of a consumer and producer of `foochan` running `true`.
+    fn test_config(args: &[&str]) -> Config {
+        let mut all = vec![
+            "pg-dispatch",
+            "--db-uri", "postgres://nobody@127.0.0.1:1/foodb",
+            "--redis-uri", "redis_uri",
+            "--channel", "foochan",
+            "--exec", "true",
+        ];
+        all.extend_from_slice(args);
+        let matches = cli::create_cli_app().get_matches_from(all);
+        Config::from_settings(&Settings::from_matches(&matches)).unwrap()
+    }
+
+    #[test]
+    fn consumer_reconnect_redis_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+
+        redis.disconnect();
+        let _ : () = redis.connection().sadd(RedisKeys::for_channel("foochan").pending_set.as_str(), "Zm9v").unwrap();
+        consumer.claim();
+        assert_eq!(dispatcher.metrics.redis_reconnects.get(), 1);
+        assert!(consumer.in_flight.is_empty());
+
+        consumer.claim();
+        assert!(consumer.in_flight.contains("Zm9v"));
+    }
+
+    #[test]
+    fn producer_reconnect_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut producer = Producer::new(&dispatcher, redis.client());
+
+        producer.enqueue("foo");
+        redis.disconnect();
+        // not lost to the broken connection
+        producer.enqueue("bar");
+        let pending: HashSet<String> = redis.connection().smembers(RedisKeys::for_channel("foochan").pending_set.as_str()).unwrap();
+        assert_eq!(pending, vec!["Zm9v".to_string(), "YmFy".to_string()].into_iter().collect());
+        assert_eq!(dispatcher.metrics.redis_reconnects.get(), 1);
+
+        // gives up on the unreachable database once shutdown is requested
+        let control = dispatcher.control.clone();
+        let stopper = thread::spawn(move|| {
+            thread::sleep(time::Duration::from_millis(100));
+            control.request_shutdown();
+        });
+        assert!(producer.reconnect_postgres().is_none());
+        stopper.join().unwrap();
+        assert_eq!(dispatcher.metrics.postgres_reconnects.get(), 0);
+    }
+
+    #[test]
+    fn consumer_attempts_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        let attempt = || -> Option<u64> { redis_conn.hget(keys.attempts.as_str(), "Zm9v").unwrap() };
+
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), "Zm9v").unwrap();
+        consumer.claim();
+        assert_eq!(attempt(), Some(1));
+
+        // claimed again after a retry
+        let _ : () = redis_conn.srem(keys.processing_set.as_str(), "Zm9v").unwrap();
+        consumer.claim();
+        assert_eq!(attempt(), Some(2));
+
+        consumer.complete("Zm9v".to_string(), true, json!({}));
+        assert_eq!(attempt(), None);
+    }
+
+    #[test]
+    fn consumer_in_flight_expires_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), "Zm9v").unwrap();
+        consumer.claim();
+        let in_flight = keys.consumer_in_flight(&consumer.registration.id);
+        let ttl: i64 = redis::cmd("TTL").arg(&in_flight).query(&redis_conn).unwrap();
+        assert_eq!(ttl, registry::HEARTBEAT_TTL as i64);
+    }
+
+    #[test]
+    fn consumer_prune_finished_test() {
+        let redis = FakeRedis::start();
+        let config = test_config(&["--done-ttl", "60", "--dead-max-entries", "1"]);
+        assert_eq!((config.dead_ttl, config.dead_max_entries), (None, Some(1)));
+        let dispatcher = Dispatcher::from_config(&config);
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        let now = unix_time();
+        for &(set, key, finished_at) in &[(&keys.done, "old", now - 120), (&keys.done, "new", now),
+                                          (&keys.dead, "first", now - 10), (&keys.dead, "last", now)] {
+            let _ : () = redis_conn.zadd(set.as_str(), key, finished_at).unwrap();
+            let _ : () = redis_conn.hset(keys.results.as_str(), key, "{}").unwrap();
+        }
+        consumer.prune_finished();
+
+        let done: Vec<String> = redis_conn.zrange(keys.done.as_str(), 0, -1).unwrap();
+        let dead: Vec<String> = redis_conn.zrange(keys.dead.as_str(), 0, -1).unwrap();
+        let results: HashMap<String, String> = redis_conn.hgetall(keys.results.as_str()).unwrap();
+        assert_eq!((done, dead), (vec!["new".to_string()], vec!["last".to_string()]));
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn consumer_claim_cancelled_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        // cancelled through the admin API between the read and the claim
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), "Zm9v").unwrap();
+        redis.before_exec(&[&["SREM", &keys.pending_set, "Zm9v"], &["ZADD", &keys.dead, "1", "Zm9v"]]);
+        consumer.claim();
+
+        let processing: bool = redis_conn.sismember(keys.processing_set.as_str(), "Zm9v").unwrap();
+        assert!(!processing);
+        assert!(consumer.in_flight.is_empty());
+    }
+
+    #[test]
+    fn consumer_rate_refund_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&["--workers", "2", "--rate-limit", "1/h"]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        // the only token is not lost to the task another consumer claims first
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), &["YmFy", "Zm9v"][..]).unwrap();
+        redis.before_exec(&[&["SADD", &keys.processing_set, "YmFy"]]);
+        consumer.claim();
+
+        assert_eq!(consumer.in_flight, vec!["Zm9v".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn first_per_concurrency_value_test() {
+        let candidate = |key: &str, value: Option<&str>| Candidate {
+            key: key.to_string(),
+            payload: String::new(),
+            concurrency_value: value.map(str::to_string),
+            priority: 0,
+        };
+        let mut candidates = vec![
+            candidate("a", Some("1")), candidate("b", None), candidate("c", Some("1")),
+            candidate("d", Some("2")), candidate("e", None), candidate("f", Some("2")),
+        ];
+        first_per_concurrency_value(&mut candidates);
+
+        let keys: Vec<&str> = candidates.iter().map(|task| task.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn consumer_claim_fifo_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&["--workers", "1", "--order", "fifo"]));
+        let consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        // the oldest arrival waits to be retried, the next one runs elsewhere
+        for &(key, arrival) in &[("a", 0), ("b", 1), ("c", 2), ("d", 3)] {
+            let _ : () = redis_conn.zadd(keys.arrival.as_str(), key, arrival).unwrap();
+        }
+        let _ : () = redis_conn.zadd(keys.delayed.as_str(), "a", unix_time() + 60).unwrap();
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), &["b", "c", "d"][..]).unwrap();
+        let _ : () = redis_conn.sadd(keys.processing_set.as_str(), "b").unwrap();
+        assert_eq!(consumer.waiting().unwrap(), (vec!["c".to_string(), "d".to_string()], 2, true));
+
+        // tasks queued before arrivals were recorded are read whole
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), "e").unwrap();
+        let (mut waiting, backlog, in_order) = consumer.waiting().unwrap();
+        waiting.sort();
+        assert_eq!((waiting, backlog, in_order), (vec!["c".to_string(), "d".to_string(), "e".to_string()], 3, false));
+    }
+
+    #[test]
+    fn consumer_claim_concurrency_key_test() {
+        let redis = FakeRedis::start();
+        let config = test_config(&["--workers", "4", "--concurrency-key", "$.account"]);
+        let dispatcher = Dispatcher::from_config(&config);
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        let key = |payload: &str| base64::encode(payload);
+
+        // the second task of account 1 arrived first
+        for &(payload, arrival) in &[(r#"{"account":1,"n":1}"#, 2), (r#"{"account":1,"n":2}"#, 1),
+                                     (r#"{"account":2,"n":3}"#, 3), (r#"{"n":4}"#, 4)] {
+            let _ : () = redis_conn.sadd(keys.pending_set.as_str(), key(payload)).unwrap();
+            let _ : () = redis_conn.zadd(keys.arrival.as_str(), key(payload), arrival).unwrap();
+        }
+        // account 1 is taken by another consumer while claiming its task
+        redis.before_exec(&[&["HSET", &keys.concurrency, "1", "b3RoZXI="],
+                            &["SADD", &keys.processing_set, "b3RoZXI="]]);
+        consumer.claim();
+
+        // only the task of account 1 that arrived first was considered
+        let expected = vec![key(r#"{"account":2,"n":3}"#), key(r#"{"n":4}"#)];
+        assert_eq!(consumer.in_flight, expected.into_iter().collect());
+        let holder: Option<String> = redis_conn.hget(keys.concurrency.as_str(), "2").unwrap();
+        assert_eq!(holder, Some(key(r#"{"account":2,"n":3}"#)));
+    }
+
+    #[test]
+    fn consumer_retry_keeps_concurrency_key_test() {
+        let redis = FakeRedis::start();
+        let config = test_config(&["--workers", "4", "--concurrency-key", "$.account", "--retry-delay", "0"]);
+        let dispatcher = Dispatcher::from_config(&config);
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        let (first, second) = (base64::encode(r#"{"account":1,"n":1}"#), base64::encode(r#"{"account":1,"n":2}"#));
+        for &(key, arrival) in &[(&first, 1), (&second, 2)] {
+            let _ : () = redis_conn.sadd(keys.pending_set.as_str(), key.as_str()).unwrap();
+            let _ : () = redis_conn.zadd(keys.arrival.as_str(), key.as_str(), arrival).unwrap();
+        }
+        consumer.claim();
+        assert_eq!(consumer.in_flight, vec![first.clone()].into_iter().collect());
+
+        // the later task still waits while the first one waits to be retried
+        let output = Output { code: Some("1".to_string()), details: json!({}) };
+        consumer.finish(WorkerMessage::Finished(first.clone(), Outcome::Retryable(output), time::Duration::from_secs(1)));
+        consumer.claim();
+        assert!(consumer.in_flight.is_empty());
+
+        // and after it is due again, as it keeps its place in line
+        consumer.release_due();
+        let arrival: Option<u64> = redis_conn.zscore(keys.arrival.as_str(), first.as_str()).unwrap();
+        assert_eq!(arrival, Some(1));
+        consumer.claim();
+        assert_eq!(consumer.in_flight, vec![first].into_iter().collect());
+    }
+
+    #[test]
+    fn consumer_max_attempts_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&["--max-attempts", "2", "--retry-delay", "0"]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        let retryable = || WorkerMessage::Finished(
+            "Zm9v".to_string(), Outcome::Retryable(Output::default()), time::Duration::from_secs(1));
+        let dead = || -> Option<u64> { redis_conn.zscore(keys.dead.as_str(), "Zm9v").unwrap() };
+
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), "Zm9v").unwrap();
+        consumer.claim();
+        consumer.finish(retryable());
+        let delayed: Option<u64> = redis_conn.zscore(keys.delayed.as_str(), "Zm9v").unwrap();
+        assert!(delayed.is_some());
+        assert_eq!(dead(), None);
+
+        // given up on after its second run
+        consumer.release_due();
+        consumer.claim();
+        consumer.finish(retryable());
+        let delayed: Option<u64> = redis_conn.zscore(keys.delayed.as_str(), "Zm9v").unwrap();
+        assert_eq!(delayed, None);
+        assert!(dead().is_some());
+        let pending: bool = redis_conn.sismember(keys.pending_set.as_str(), "Zm9v").unwrap();
+        assert!(!pending);
+        assert_eq!(dispatcher.metrics.tasks_failed.get(), 1);
+    }
+
+    #[test]
+    fn consumer_release_task_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut consumer = Consumer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+        let pending = || -> bool { redis_conn.sismember(keys.pending_set.as_str(), "Zm9v").unwrap() };
+
+        let _ : () = redis_conn.zadd(keys.delayed.as_str(), "Zm9v", unix_time() - 1).unwrap();
+        assert!(consumer.release_task("Zm9v").unwrap());
+        consumer.claim();
+        consumer.complete("Zm9v".to_string(), true, json!({}));
+
+        // another consumer read the task as due before it was released
+        assert!(!consumer.release_task("Zm9v").unwrap());
+        assert!(!consumer.release_task("Zm9v").unwrap());
+        assert!(!pending());
+
+        // or releases it while this one does
+        let _ : () = redis_conn.zadd(keys.delayed.as_str(), "Zm9v", unix_time() - 1).unwrap();
+        redis.before_exec(&[&["ZREM", &keys.delayed, "Zm9v"], &["SADD", &keys.pending_set, "Zm9v"]]);
+        assert!(!consumer.release_task("Zm9v").unwrap());
+        assert!(pending());
+        let enqueued_at: Option<u64> = redis_conn.zscore(keys.enqueued_at.as_str(), "Zm9v").unwrap();
+        assert_eq!(enqueued_at, None);
+    }
+
+    #[test]
+    fn producer_reload_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut producer = Producer::new(&dispatcher, redis.client());
+        let pending = || -> Vec<String> {
+            redis.connection().smembers(RedisKeys::for_channel("foochan").pending_set.as_str()).unwrap()
+        };
+
+        producer.enqueue(r#"{"op":"DELETE","n":1}"#);
+        assert_eq!(pending().len(), 1);
+
+        dispatcher.control.request_reload(test_config(&["--filter", "$.op != DELETE"]));
+        producer.reload(dispatcher.control.take_producer_reload().unwrap());
+        producer.enqueue(r#"{"op":"DELETE","n":2}"#);
+        assert_eq!(pending().len(), 1);
+        assert_eq!(dispatcher.metrics.notifications_filtered.get(), 1);
+        // the consumer gets its own copy
+        assert!(dispatcher.control.take_reload().is_some());
+    }
+
+    #[test]
+    fn producer_skips_waiting_tasks_test() {
+        let redis = FakeRedis::start();
+        let dispatcher = Dispatcher::from_config(&test_config(&[]));
+        let mut producer = Producer::new(&dispatcher, redis.client());
+        let (keys, redis_conn) = (RedisKeys::for_channel("foochan"), redis.connection());
+
+        // notified again while delayed, then while pending
+        let later = format!(r#"{{"run_at":{}}}"#, unix_time() + 3600);
+        let _ : () = redis_conn.zadd(keys.delayed.as_str(), base64::encode("foo"), unix_time() + 60).unwrap();
+        let _ : () = redis_conn.sadd(keys.pending_set.as_str(), base64::encode(&later)).unwrap();
+        producer.enqueue("foo");
+        producer.enqueue(&later);
+
+        let pending: Vec<String> = redis_conn.smembers(keys.pending_set.as_str()).unwrap();
+        let delayed: Vec<String> = redis_conn.zrange(keys.delayed.as_str(), 0, -1).unwrap();
+        assert_eq!((pending, delayed), (vec![base64::encode(&later)], vec![base64::encode("foo")]));
+    }
 }
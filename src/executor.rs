@@ -0,0 +1,205 @@
+extern crate serde_json;
+
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use self::serde_json::Value;
+use log;
+use payload;
+
+/// How often executors check on the children running their tasks.
+pub const CHILD_POLL_INTERVAL: u64 = 50;
+
+/// A task handed to an executor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    pub payload: String,
+    /// The base64 key of the task in Redis.
+    pub key: String,
+    /// Worker running the task, for the logs.
+    pub worker: usize,
+    /// How many times the task was claimed, counting this one.
+    pub attempt: u64,
+}
+
+impl Task {
+    /// Short id of the task for the logs.
+    pub fn id(&self) -> String {
+        payload::task_id(&self.key)
+    }
+}
+
+/// What the handler of a task reported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Output {
+    /// Exit code of a command, status code of a response or SQLSTATE of a
+    /// statement, counted in the metrics. `None` when there is none, e.g. for
+    /// a killed command.
+    pub code: Option<String>,
+    /// Recorded in the result of the task, e.g. `{"exit_code": 0}`.
+    pub details: Value,
+}
+
+/// How running a task went.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Success(Output),
+    /// Worth trying again later, e.g. the handler could not be reached.
+    Retryable(Output),
+    /// Trying again would fail the same way.
+    Permanent(Output),
+    /// The handler did not finish in time.
+    Timeout(Output),
+}
+
+impl Outcome {
+    pub fn output(&self) -> &Output {
+        match *self {
+            Outcome::Success(ref output)
+                | Outcome::Retryable(ref output)
+                | Outcome::Permanent(ref output)
+                | Outcome::Timeout(ref output) => output
+        }
+    }
+}
+
+/// Runs tasks for the workers of a `ThreadPool`, e.g. by spawning a command.
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Runs a task, stopping early if it can when `abort` is set.
+    fn execute(&self, task: &Task, abort: &AtomicBool) -> Outcome;
+}
+
+/// Runs a command with the payload on its standard input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandExecutor {
+    command_vector: Vec<OsString>,
+}
+
+impl CommandExecutor {
+    pub fn new(command_vector: Vec<OsString>) -> CommandExecutor {
+        CommandExecutor { command_vector }
+    }
+}
+
+impl Executor for CommandExecutor {
+    /// Kills the child when aborted.
+    fn execute(&self, task: &Task, abort: &AtomicBool) -> Outcome {
+        let program = &self.command_vector[0];
+        let program_arguments = &self.command_vector[1..];
+
+        // spawn child command
+        // TODO: if a thread panics, does the threadpool replaces them?
+        let started_at = Instant::now();
+        let child_command =
+            Command::new(program)
+            .args(program_arguments)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+
+        let mut child = match child_command {
+            Ok(child) => child,
+            Err(_) => {
+                log::error("couldn't execute program")
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("program", program.to_string_lossy().as_ref())
+                    .emit();
+                return Outcome::Retryable(Output::default());
+            }
+        };
+
+        // forward the standard streams while the child runs
+        let forwarders = vec![
+            forward_lines(child.stdout.take().unwrap(), "stdout", task),
+            forward_lines(child.stderr.take().unwrap(), "stderr", task),
+        ];
+
+        // pass payload data through child process stdin
+        let write_to_child = child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(task.payload.as_bytes());
+        if write_to_child.is_err() {
+            let _ = child.kill();
+            let _ = child.wait();
+            log::error("couldn't write to child process stdin")
+                .field("worker", task.worker)
+                .field("task", task.id())
+                .field("attempt", task.attempt)
+                .emit();
+            return Outcome::Retryable(Output::default());
+        }
+
+        let exit_status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if abort.load(Ordering::SeqCst) {
+                let _ = child.kill();
+                break child.wait().unwrap();
+            }
+            thread::sleep(Duration::from_millis(CHILD_POLL_INTERVAL));
+        };
+        for forwarder in forwarders {
+            let _ = forwarder.join();
+        }
+
+        let output = Output {
+            code: exit_status.code().map(|code| code.to_string()),
+            details: json!({ "exit_code": exit_status.code() }),
+        };
+        if abort.load(Ordering::SeqCst) && !exit_status.success() {
+            log::warn("command aborted")
+                .field("worker", task.worker)
+                .field("task", task.id())
+                .field("attempt", task.attempt)
+                .field("program", program.to_string_lossy().as_ref())
+                .emit();
+            return Outcome::Retryable(output);
+        }
+        let event = match exit_status.success() {
+            true => log::info("command succeeded"),
+            false => log::warn("command failed"),
+        };
+        event
+            .field("worker", task.worker)
+            .field("task", task.id())
+            .field("attempt", task.attempt)
+            .field("program", program.to_string_lossy().as_ref())
+            .field("exit_code", exit_status.code())
+            .field("duration", log::seconds(started_at.elapsed()))
+            .emit();
+        match exit_status.success() {
+            true => Outcome::Success(output),
+            false => Outcome::Permanent(output),
+        }
+    }
+}
+
+/// Logs every line a child writes to `stream`, tagged with its task.
+fn forward_lines<R: Read + Send + 'static>(
+    stream: R, name: &'static str, task: &Task) -> thread::JoinHandle<()> {
+    let (id, worker, attempt) = (task.id(), task.worker, task.attempt);
+    thread::spawn(move|| {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => log::info("command output")
+                    .field("worker", worker)
+                    .field("task", id.as_str())
+                    .field("attempt", attempt)
+                    .field("stream", name)
+                    .field("line", line)
+                    .emit(),
+                Err(_) => break,
+            }
+        }
+    })
+}
@@ -0,0 +1,532 @@
+//! An in-memory server speaking enough of the Redis protocol for tests of the
+//! dispatcher's use of Redis. Expiry times are kept for `TTL` but keys never
+//! expire, and blocking commands are not supported.
+extern crate redis;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Bytes = Vec<u8>;
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(Bytes),
+    Hash(BTreeMap<Bytes, Bytes>),
+    Set(BTreeSet<Bytes>),
+    SortedSet(BTreeMap<Bytes, f64>),
+}
+
+/// A hook run right before the next `EXEC`, to race a transaction.
+type Hook = Box<dyn FnOnce(&mut Store) + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Nil,
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Array(Vec<Reply>),
+}
+
+/// The keys of the server, with a version bumped on every write for `WATCH`.
+#[derive(Default)]
+pub struct Store {
+    values: HashMap<Bytes, Value>,
+    versions: HashMap<Bytes, u64>,
+    /// Seconds to live given by `EXPIRE`, not counting down.
+    ttls: HashMap<Bytes, i64>,
+    before_exec: Option<Hook>,
+}
+
+pub struct FakeRedis {
+    port: u16,
+    store: Arc<Mutex<Store>>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl FakeRedis {
+    pub fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let store = Arc::new(Mutex::new(Store::default()));
+        let streams = Arc::new(Mutex::new(Vec::new()));
+
+        let (shared_store, shared_streams) = (store.clone(), streams.clone());
+        thread::spawn(move|| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                shared_streams.lock().unwrap().push(stream.try_clone().unwrap());
+                let store = shared_store.clone();
+                thread::spawn(move|| serve(stream, &store));
+            }
+        });
+        FakeRedis { port, store, streams }
+    }
+
+    pub fn client(&self) -> redis::Client {
+        redis::Client::open(format!("redis://127.0.0.1:{}/", self.port).as_str()).unwrap()
+    }
+
+    pub fn connection(&self) -> redis::Connection {
+        self.client().get_connection().unwrap()
+    }
+
+    /// Drops every open connection, as a restarting server would.
+    pub fn disconnect(&self) {
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Runs `commands` right before the next `EXEC`, as another client
+    /// changing watched keys would.
+    pub fn before_exec(&self, commands: &[&[&str]]) {
+        let commands: Vec<Vec<Bytes>> = commands.iter()
+            .map(|args| args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+            .collect();
+        self.store.lock().unwrap().before_exec = Some(Box::new(move|store: &mut Store| {
+            for args in &commands {
+                store.execute(args);
+            }
+        }));
+    }
+}
+
+impl Store {
+    fn version(&self, key: &[u8]) -> u64 {
+        self.versions.get(key).cloned().unwrap_or(0)
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        *self.versions.entry(key.to_vec()).or_insert(0) += 1;
+    }
+
+    fn hash(&mut self, key: &[u8]) -> Result<&mut BTreeMap<Bytes, Bytes>, Reply> {
+        match *self.values.entry(key.to_vec()).or_insert_with(|| Value::Hash(BTreeMap::new())) {
+            Value::Hash(ref mut hash) => Ok(hash),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn set(&mut self, key: &[u8]) -> Result<&mut BTreeSet<Bytes>, Reply> {
+        match *self.values.entry(key.to_vec()).or_insert_with(|| Value::Set(BTreeSet::new())) {
+            Value::Set(ref mut set) => Ok(set),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn sorted_set(&mut self, key: &[u8]) -> Result<&mut BTreeMap<Bytes, f64>, Reply> {
+        match *self.values.entry(key.to_vec()).or_insert_with(|| Value::SortedSet(BTreeMap::new())) {
+            Value::SortedSet(ref mut zset) => Ok(zset),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    /// Members of a sorted set ordered by score, then member.
+    fn ranked(&mut self, key: &[u8]) -> Result<Vec<(Bytes, f64)>, Reply> {
+        let mut members: Vec<(Bytes, f64)> = self.sorted_set(key)?
+            .iter().map(|(member, score)| (member.clone(), *score)).collect();
+        members.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+        Ok(members)
+    }
+
+    /// Drops keys left empty, as Redis does.
+    fn clean(&mut self, key: &[u8]) {
+        let empty = match self.values.get(key) {
+            Some(Value::Hash(hash)) => hash.is_empty(),
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::SortedSet(zset)) => zset.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.values.remove(key);
+        }
+        if !self.values.contains_key(key) {
+            self.ttls.remove(key);
+        }
+    }
+
+    fn execute(&mut self, args: &[Bytes]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let key = args.get(1).cloned().unwrap_or_default();
+        let reply = match self.command(&name, &key, &args[1..]) {
+            Ok(reply) | Err(reply) => reply,
+        };
+        let writes = matches!(
+            name.as_str(),
+            "SET" | "DEL" | "INCR" | "INCRBY" | "EXPIRE" | "PEXPIRE" | "HSET" | "HMSET" | "HDEL" | "HINCRBY" | "SADD"
+            | "SREM" | "ZADD" | "ZREM" | "ZREMRANGEBYSCORE");
+        if writes {
+            let keys: Vec<Bytes> = match name.as_str() {
+                "DEL" => args[1..].to_vec(),
+                _ => vec![key.clone()],
+            };
+            for key in keys {
+                self.touch(&key);
+                self.clean(&key);
+            }
+        } else if !key.is_empty() {
+            self.clean(&key);
+        }
+        reply
+    }
+
+    fn command(&mut self, name: &str, key: &[u8], args: &[Bytes]) -> Result<Reply, Reply> {
+        Ok(match name {
+            "PING" => Reply::Status("PONG"),
+            "SELECT" => Reply::Integer(1),
+            "EXPIRE" | "PEXPIRE" => {
+                if !self.values.contains_key(key) {
+                    return Ok(Reply::Integer(0));
+                }
+                let ttl = match name {
+                    "EXPIRE" => int(&args[1])?,
+                    _ => int(&args[1])? / 1000,
+                };
+                self.ttls.insert(key.to_vec(), ttl);
+                Reply::Integer(1)
+            },
+            "TTL" => Reply::Integer(match self.values.contains_key(key) {
+                true => self.ttls.get(key).cloned().unwrap_or(-1),
+                false => -2,
+            }),
+            "PUBLISH" => Reply::Integer(0),
+            "GET" => match self.values.get(key) {
+                Some(Value::String(value)) => Reply::Bulk(value.clone()),
+                Some(_) => return Err(wrong_type()),
+                None => Reply::Nil,
+            },
+            "SET" => {
+                let nx = args[2..].iter().any(|arg| arg.eq_ignore_ascii_case(b"NX"));
+                if nx && self.values.contains_key(key) {
+                    return Ok(Reply::Nil);
+                }
+                self.values.insert(key.to_vec(), Value::String(args[1].clone()));
+                Reply::Status("OK")
+            },
+            "DEL" => Reply::Integer(args.iter().filter(|key| self.values.remove(*key).is_some()).count() as i64),
+            "EXISTS" => Reply::Integer(self.values.contains_key(key) as i64),
+            "INCR" | "INCRBY" => {
+                let by = match args.get(1) {
+                    Some(by) => int(by)?,
+                    None => 1,
+                };
+                let value = match self.values.get(key) {
+                    Some(Value::String(value)) => int(value)?,
+                    Some(_) => return Err(wrong_type()),
+                    None => 0,
+                } + by;
+                self.values.insert(key.to_vec(), Value::String(value.to_string().into_bytes()));
+                Reply::Integer(value)
+            },
+            "HGET" => optional(self.hash(key)?.get(&args[1]).cloned()),
+            "HMGET" => {
+                let hash = self.hash(key)?;
+                Reply::Array(args[1..].iter().map(|field| optional(hash.get(field).cloned())).collect())
+            },
+            "HSET" | "HMSET" => {
+                let hash = self.hash(key)?;
+                let added = args[1..].chunks(2)
+                    .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                    .count();
+                match name {
+                    "HSET" => Reply::Integer(added as i64),
+                    _ => Reply::Status("OK"),
+                }
+            },
+            "HDEL" => {
+                let hash = self.hash(key)?;
+                Reply::Integer(args[1..].iter().filter(|field| hash.remove(*field).is_some()).count() as i64)
+            },
+            "HINCRBY" => {
+                let by = int(&args[2])?;
+                let hash = self.hash(key)?;
+                let value = hash.get(&args[1]).map_or(Ok(0), |value| int(value))? + by;
+                hash.insert(args[1].clone(), value.to_string().into_bytes());
+                Reply::Integer(value)
+            },
+            "HGETALL" => Reply::Array(self.hash(key)?.iter()
+                .flat_map(|(field, value)| vec![Reply::Bulk(field.clone()), Reply::Bulk(value.clone())])
+                .collect()),
+            "SADD" => {
+                let set = self.set(key)?;
+                Reply::Integer(args[1..].iter().filter(|member| set.insert(member.to_vec())).count() as i64)
+            },
+            "SREM" => {
+                let set = self.set(key)?;
+                Reply::Integer(args[1..].iter().filter(|member| set.remove(*member)).count() as i64)
+            },
+            "SISMEMBER" => Reply::Integer(self.set(key)?.contains(&args[1]) as i64),
+            "SCARD" => Reply::Integer(self.set(key)?.len() as i64),
+            "SMEMBERS" => bulks(self.set(key)?.iter().cloned()),
+            "SDIFF" => {
+                let mut diff = self.set(key)?.clone();
+                for other in &args[1..] {
+                    for member in self.set(other)?.iter() {
+                        diff.remove(member);
+                    }
+                    self.clean(other);
+                }
+                bulks(diff.into_iter())
+            },
+            "SSCAN" => Reply::Array(vec![Reply::Bulk(b"0".to_vec()), bulks(self.set(key)?.iter().cloned())]),
+            "SCAN" => {
+                let pattern = args.iter().position(|arg| arg.eq_ignore_ascii_case(b"MATCH"))
+                    .map(|at| args[at + 1].clone()).unwrap_or_else(|| b"*".to_vec());
+                let prefix = pattern.split(|byte| *byte == b'*').next().unwrap_or(&[]).to_vec();
+                let keys = self.values.keys().filter(|key| key.starts_with(&prefix)).cloned();
+                Reply::Array(vec![Reply::Bulk(b"0".to_vec()), bulks(keys)])
+            },
+            "ZADD" => {
+                let nx = args[1].eq_ignore_ascii_case(b"NX");
+                let pairs = if nx { &args[2..] } else { &args[1..] };
+                let zset = self.sorted_set(key)?;
+                let mut added = 0;
+                for pair in pairs.chunks(2) {
+                    let score = float(&pair[0])?;
+                    if !zset.contains_key(&pair[1]) {
+                        added += 1;
+                    } else if nx {
+                        continue;
+                    }
+                    zset.insert(pair[1].clone(), score);
+                }
+                Reply::Integer(added)
+            },
+            "ZREM" => {
+                let zset = self.sorted_set(key)?;
+                Reply::Integer(args[1..].iter().filter(|member| zset.remove(*member).is_some()).count() as i64)
+            },
+            "ZSCORE" => optional(self.sorted_set(key)?.get(&args[1]).map(|score| format_score(*score))),
+            "ZMSCORE" => {
+                let zset = self.sorted_set(key)?;
+                Reply::Array(args[1..].iter()
+                    .map(|member| optional(zset.get(member).map(|score| format_score(*score))))
+                    .collect())
+            },
+            "ZCARD" => Reply::Integer(self.sorted_set(key)?.len() as i64),
+            "ZRANGE" | "ZREVRANGE" => {
+                let mut members = self.ranked(key)?;
+                if name == "ZREVRANGE" {
+                    members.reverse();
+                }
+                let len = members.len() as i64;
+                let index = |at: i64| if at < 0 { len + at } else { at };
+                let (start, stop) = (index(int(&args[1])?).max(0), index(int(&args[2])?).min(len - 1));
+                let members = match start <= stop {
+                    true => members[start as usize..=stop as usize].to_vec(),
+                    false => Vec::new(),
+                };
+                with_scores(members, args[3..].iter().any(|arg| arg.eq_ignore_ascii_case(b"WITHSCORES")))
+            },
+            "ZRANGEBYSCORE" | "ZREMRANGEBYSCORE" => {
+                let (min, max) = (bound(&args[1])?, bound(&args[2])?);
+                let mut members: Vec<(Bytes, f64)> = self.ranked(key)?.into_iter()
+                    .filter(|&(_, score)| min.is_none_or(|min| score >= min) && max.is_none_or(|max| score <= max))
+                    .collect();
+                if name == "ZREMRANGEBYSCORE" {
+                    let zset = self.sorted_set(key)?;
+                    for (member, _) in &members {
+                        zset.remove(member);
+                    }
+                    return Ok(Reply::Integer(members.len() as i64));
+                }
+                if let Some(at) = args.iter().position(|arg| arg.eq_ignore_ascii_case(b"LIMIT")) {
+                    let (offset, count) = (int(&args[at + 1])? as usize, int(&args[at + 2])?);
+                    members = members.into_iter().skip(offset)
+                        .take(if count < 0 { usize::MAX } else { count as usize }).collect();
+                }
+                with_scores(members, args[3..].iter().any(|arg| arg.eq_ignore_ascii_case(b"WITHSCORES")))
+            },
+            _ => return Err(Reply::Error(format!("ERR unknown command '{}'", name))),
+        })
+    }
+}
+
+/// Per-connection state: the keys watched and the commands queued by `MULTI`.
+#[derive(Default)]
+struct Session {
+    watched: Vec<(Bytes, u64)>,
+    queued: Option<Vec<Vec<Bytes>>>,
+}
+
+impl Session {
+    fn handle(&mut self, store: &mut Store, args: Vec<Bytes>) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        match name.as_str() {
+            "MULTI" => {
+                self.queued = Some(Vec::new());
+                Reply::Status("OK")
+            },
+            "DISCARD" => {
+                self.queued = None;
+                self.watched.clear();
+                Reply::Status("OK")
+            },
+            "WATCH" => {
+                for key in &args[1..] {
+                    self.watched.push((key.clone(), store.version(key)));
+                }
+                Reply::Status("OK")
+            },
+            "UNWATCH" => {
+                self.watched.clear();
+                Reply::Status("OK")
+            },
+            "EXEC" => {
+                let queued = match self.queued.take() {
+                    Some(queued) => queued,
+                    None => return Reply::Error("ERR EXEC without MULTI".to_string()),
+                };
+                if let Some(hook) = store.before_exec.take() {
+                    hook(store);
+                }
+                let changed = self.watched.drain(..).any(|(key, version)| store.version(&key) != version);
+                match changed {
+                    true => Reply::Nil,
+                    false => Reply::Array(queued.iter().map(|args| store.execute(args)).collect()),
+                }
+            },
+            _ => match self.queued {
+                Some(ref mut queued) => {
+                    queued.push(args);
+                    Reply::Status("QUEUED")
+                },
+                None => store.execute(&args),
+            },
+        }
+    }
+}
+
+fn serve(stream: TcpStream, store: &Mutex<Store>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let mut session = Session::default();
+
+    while let Ok(Some(args)) = read_command(&mut reader) {
+        if args.is_empty() {
+            continue;
+        }
+        let reply = session.handle(&mut store.lock().unwrap(), args);
+        let mut out = Vec::new();
+        encode(&reply, &mut out);
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads a command sent as an array of bulk strings, `None` at the end of
+/// the stream.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Bytes>>> {
+    let count = match read_line(reader)? {
+        Some(line) => parse_len(&line, b'*')?,
+        None => return Ok(None),
+    };
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let len = match read_line(reader)? {
+            Some(line) => parse_len(&line, b'$')?,
+            None => return Ok(None),
+        };
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Bytes>> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line)? {
+        0 => Ok(None),
+        _ => {
+            while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+                line.pop();
+            }
+            Ok(Some(line))
+        }
+    }
+}
+
+fn parse_len(line: &[u8], prefix: u8) -> io::Result<usize> {
+    match line.split_first() {
+        Some((first, len)) if *first == prefix => String::from_utf8_lossy(len).parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad length")),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected an array of bulk strings")),
+    }
+}
+
+fn encode(reply: &Reply, out: &mut Bytes) {
+    match *reply {
+        Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+        Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+        Reply::Error(ref error) => out.extend_from_slice(format!("-{}\r\n", error).as_bytes()),
+        Reply::Integer(value) => out.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+        Reply::Bulk(ref value) => {
+            out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        },
+        Reply::Array(ref items) => {
+            out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode(item, out);
+            }
+        },
+    }
+}
+
+fn wrong_type() -> Reply {
+    Reply::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+}
+
+fn int(value: &[u8]) -> Result<i64, Reply> {
+    String::from_utf8_lossy(value).parse()
+        .map_err(|_| Reply::Error("ERR value is not an integer or out of range".to_string()))
+}
+
+fn float(value: &[u8]) -> Result<f64, Reply> {
+    String::from_utf8_lossy(value).parse()
+        .map_err(|_| Reply::Error("ERR value is not a valid float".to_string()))
+}
+
+/// A score bound of `ZRANGEBYSCORE`, `None` for an infinite one.
+fn bound(value: &[u8]) -> Result<Option<f64>, Reply> {
+    match value {
+        b"-inf" | b"+inf" | b"inf" => Ok(None),
+        value => float(value).map(Some),
+    }
+}
+
+fn format_score(score: f64) -> Bytes {
+    score.to_string().into_bytes()
+}
+
+fn optional(value: Option<Bytes>) -> Reply {
+    value.map_or(Reply::Nil, Reply::Bulk)
+}
+
+fn bulks<I: Iterator<Item = Bytes>>(values: I) -> Reply {
+    Reply::Array(values.map(Reply::Bulk).collect())
+}
+
+fn with_scores(members: Vec<(Bytes, f64)>, scores: bool) -> Reply {
+    Reply::Array(members.into_iter()
+        .flat_map(|(member, score)| match scores {
+            true => vec![Reply::Bulk(member), Reply::Bulk(format_score(score))],
+            false => vec![Reply::Bulk(member)],
+        })
+        .collect())
+}
@@ -0,0 +1,96 @@
+extern crate serde_json;
+
+use self::serde_json::Value;
+use payload;
+
+/// What the value at the path of a filter is compared with.
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Equals(Value),
+    In(Vec<Value>),
+}
+
+/// A condition notifications have to meet to be enqueued, e.g.
+/// `$.op != "DELETE"` or `$.table in ["orders","refunds"]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    path: String,
+    test: Test,
+    /// Whether the operator is `!=` or `not in`.
+    negated: bool,
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Filter, String> {
+        let invalid = || format!(
+            "invalid filter {}, expected e.g. $.op != \"DELETE\" or $.table in [\"orders\",\"refunds\"]", filter);
+        let filter = filter.trim();
+        if !filter.starts_with("$.") {
+            return Err(invalid());
+        }
+
+        // the first operator found, so that values may contain the others
+        let (at, operator) = ["==", "!=", " not in ", " in "].iter()
+            .filter_map(|operator| filter.find(operator).map(|at| (at, *operator)))
+            .min_by_key(|&(at, _)| at)
+            .ok_or_else(invalid)?;
+        let path = filter[..at].trim().to_string();
+        let value = filter[at + operator.len()..].trim();
+        if path.len() <= 2 || value.is_empty() {
+            return Err(invalid());
+        }
+
+        let test = match operator {
+            "==" | "!=" => Test::Equals(payload::parse_value(value)),
+            _ => match serde_json::from_str(value) {
+                Ok(Value::Array(values)) => Test::In(values),
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(Filter { path, test, negated: operator == "!=" || operator == " not in " })
+    }
+
+    /// Payloads that are not JSON, or have nothing at the path, only pass
+    /// `!=` and `not in` filters.
+    pub fn matches(&self, payload: &str) -> bool {
+        let found = payload::json_at(payload, &self.path).is_some_and(|value| match self.test {
+            Test::Equals(ref expected) => value == *expected,
+            Test::In(ref expected) => expected.contains(&value),
+        });
+        found != self.negated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_test() {
+        let not_delete = Filter::parse(r#"$.op != "DELETE""#).unwrap();
+        assert!(not_delete.matches(r#"{"op":"INSERT"}"#));
+        assert!(!not_delete.matches(r#"{"op":"DELETE"}"#));
+        assert!(not_delete.matches("not json"));
+
+        let tables = Filter::parse(r#"$.table in ["orders","refunds"]"#).unwrap();
+        assert!(tables.matches(r#"{"table":"refunds"}"#));
+        assert!(!tables.matches(r#"{"table":"users"}"#));
+        assert!(!tables.matches("not json"));
+
+        let not_tables = Filter::parse(r#"$.table not in ["orders"]"#).unwrap();
+        assert!(not_tables.matches(r#"{"table":"users"}"#));
+        assert!(!not_tables.matches(r#"{"table":"orders"}"#));
+
+        assert!(Filter::parse("$.row.id == 3").unwrap().matches(r#"{"row":{"id":3}}"#));
+        assert!(Filter::parse("$.op == INSERT").unwrap().matches(r#"{"op":"INSERT"}"#));
+        assert!(Filter::parse(r#"$.name != "a in b""#).unwrap().matches(r#"{"name":"c"}"#));
+    }
+
+    #[test]
+    fn filter_parse_test() {
+        assert!(Filter::parse("op != DELETE").is_err());
+        assert!(Filter::parse("$.op").is_err());
+        assert!(Filter::parse("$.op ==").is_err());
+        assert!(Filter::parse(r#"$.table in "orders""#).is_err());
+    }
+}
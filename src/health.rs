@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds without a consumer loop iteration after which it counts as stuck.
+const CONSUMER_TICK_TIMEOUT: usize = 30;
+
+/// Liveness and readiness of the producer and consumer, updated as they run
+/// and checked by `/healthz` and `/readyz`.
+#[derive(Debug, Default)]
+pub struct Health {
+    producer: bool,
+    consumer: bool,
+    producer_running: AtomicBool,
+    consumer_running: AtomicBool,
+    listening: AtomicBool,
+    /// Unix time of the last consumer loop iteration.
+    consumer_tick: AtomicUsize,
+    dead_workers: AtomicUsize,
+}
+
+/// Outcome of a set of named checks.
+#[derive(Debug)]
+pub struct Report {
+    pub checks: Vec<(&'static str, Result<(), String>)>,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+
+    pub fn render(&self) -> String {
+        self.checks.iter().map(|&(name, ref result)| match *result {
+            Ok(()) => format!("{}: ok\n", name),
+            Err(ref error) => format!("{}: {}\n", name, error),
+        }).collect()
+    }
+}
+
+impl Health {
+    pub fn new(producer: bool, consumer: bool) -> Health {
+        Health { producer, consumer, ..Health::default() }
+    }
+
+    pub fn producer_started(&self) {
+        self.producer_running.store(true, Ordering::SeqCst);
+    }
+
+    pub fn producer_stopped(&self) {
+        self.producer_running.store(false, Ordering::SeqCst);
+        self.listening.store(false, Ordering::SeqCst);
+    }
+
+    /// Whether the producer holds an active `LISTEN`.
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    pub fn consumer_started(&self) {
+        self.consumer_running.store(true, Ordering::SeqCst);
+        self.consumer_tick.store(now(), Ordering::SeqCst);
+    }
+
+    pub fn consumer_stopped(&self) {
+        self.consumer_running.store(false, Ordering::SeqCst);
+    }
+
+    /// Called on every consumer loop iteration.
+    pub fn consumer_ticked(&self, dead_workers: usize) {
+        self.consumer_tick.store(now(), Ordering::SeqCst);
+        self.dead_workers.store(dead_workers, Ordering::SeqCst);
+    }
+
+    /// Whether the threads doing the work are still alive.
+    pub fn liveness(&self) -> Report {
+        let mut checks = Vec::new();
+
+        if self.producer {
+            checks.push(("producer", running(&self.producer_running)));
+        }
+        if self.consumer {
+            checks.push(("consumer", running(&self.consumer_running)));
+            checks.push(("workers", match self.dead_workers.load(Ordering::SeqCst) {
+                0 => Ok(()),
+                dead => Err(format!("{} worker thread(s) died", dead)),
+            }));
+        }
+
+        Report { checks }
+    }
+
+    /// Whether notifications are being received and tasks executed, given
+    /// the outcome of a Redis round trip.
+    pub fn readiness(&self, redis: Result<(), String>) -> Report {
+        let mut checks = vec![("redis", redis)];
+
+        if self.producer {
+            checks.push(("listen", match self.listening.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err("not listening to the channel".to_string()),
+            }));
+        }
+        if self.consumer {
+            let idle_for = now().saturating_sub(self.consumer_tick.load(Ordering::SeqCst));
+            checks.push(("consumer", match running(&self.consumer_running) {
+                Ok(()) if idle_for > CONSUMER_TICK_TIMEOUT =>
+                    Err(format!("no consumer loop iteration for {} seconds", idle_for)),
+                result => result,
+            }));
+        }
+
+        Report { checks }
+    }
+}
+
+fn running(flag: &AtomicBool) -> Result<(), String> {
+    match flag.load(Ordering::SeqCst) {
+        true => Ok(()),
+        false => Err("not running".to_string()),
+    }
+}
+
+fn now() -> usize {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as usize).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_reports_test() {
+        let health = Health::new(true, true);
+        assert!(!health.liveness().ok());
+
+        health.producer_started();
+        health.consumer_started();
+        assert!(health.liveness().ok());
+        assert!(!health.readiness(Ok(())).ok());
+
+        health.set_listening(true);
+        assert!(health.readiness(Ok(())).ok());
+        assert!(!health.readiness(Err("down".to_string())).ok());
+
+        health.consumer_ticked(1);
+        let liveness = health.liveness();
+        assert!(!liveness.ok());
+        assert_eq!(
+            liveness.render(),
+            "producer: ok\nconsumer: ok\nworkers: 1 worker thread(s) died\n");
+    }
+}
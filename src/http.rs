@@ -0,0 +1,204 @@
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log;
+use self::serde_json::Value;
+
+/// Largest request body accepted.
+const MAX_BODY: usize = 1 << 20;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path as sent, still percent-encoded so that `%2F` stays apart from `/`.
+    pub path: String,
+    /// Decoded query string parameters.
+    pub query: HashMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: u16, body: String) -> Response {
+        Response { status, content_type: "text/plain; charset=utf-8", body }
+    }
+
+    pub fn json(status: u16, body: &Value) -> Response {
+        Response { status, content_type: "application/json", body: format!("{}\n", body) }
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found\n".to_string())
+    }
+}
+
+/// Serves HTTP/1.1 requests on `addr`, one connection per thread and one
+/// request per connection.
+pub fn serve<F>(addr: &str, handler: F) -> io::Result<thread::JoinHandle<()>>
+    where F: Fn(&Request) -> Response + Send + Sync + 'static {
+    let listener = TcpListener::bind(addr)?;
+    let handler = Arc::new(handler);
+
+    Ok(thread::spawn(move|| {
+        for stream in listener.incoming() {
+            let handler = handler.clone();
+            if let Ok(stream) = stream {
+                thread::spawn(move|| {
+                    if let Err(error) = handle_connection(stream, &*handler) {
+                        log::warn("error handling request").field("error", error.to_string()).emit();
+                    }
+                });
+            }
+        }
+    }))
+}
+
+fn handle_connection<F>(stream: TcpStream, handler: &F) -> io::Result<()>
+    where F: Fn(&Request) -> Response {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let response = match read_request(&mut reader)? {
+        Some(request) => handler(&request),
+        None => Response::text(400, "bad request\n".to_string()),
+    };
+
+    write_response(stream, &response)
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Ok(None),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse::<usize>() {
+                    Ok(length) if length <= MAX_BODY => length,
+                    _ => return Ok(None),
+                };
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let body = match String::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return Ok(None),
+    };
+
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or("/").to_string();
+    let query = target.next().unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            (percent_decode(pair.next().unwrap_or("")), percent_decode(pair.next().unwrap_or("")))
+        })
+        .collect();
+
+    Ok(Some(Request { method, path, query, body }))
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' if i + 2 < bytes.len() =>
+                str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, reason(response.status), response.content_type, response.body.len())?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_request_test() {
+        let mut raw = Cursor::new(
+            "GET /metrics?foo=bar HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = read_request(&mut raw).unwrap().unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+        assert_eq!(request.query["foo"], "bar");
+        assert!(read_request(&mut Cursor::new("\r\n")).unwrap().is_none());
+
+        let mut raw = Cursor::new(
+            "POST /api/workers?state=a%2Bb%3D HTTP/1.1\r\nContent-Length: 14\r\n\r\n{\"workers\": 8}");
+        let request = read_request(&mut raw).unwrap().unwrap();
+
+        assert_eq!(request.query["state"], "a+b=");
+        assert_eq!(request.body, "{\"workers\": 8}");
+        assert_eq!(percent_decode("50%"), "50%");
+    }
+}
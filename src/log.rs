@@ -0,0 +1,221 @@
+extern crate serde_json;
+
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use self::serde_json::{Map, Value};
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static JSON: AtomicBool = AtomicBool::new(false);
+static CHANNEL: Mutex<String> = Mutex::new(String::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!("unknown log level {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Sets the level and format of every following event, all of them tagged
+/// with `channel`. Can be called again, e.g. on reload.
+pub fn init(channel: &str, level: Level, format: Format) {
+    *CHANNEL.lock().unwrap() = channel.to_string();
+    LEVEL.store(level as usize, Ordering::SeqCst);
+    JSON.store(format == Format::Json, Ordering::SeqCst);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= LEVEL.load(Ordering::SeqCst)
+}
+
+pub fn error<M: Into<String>>(message: M) -> Event {
+    Event::new(Level::Error, message)
+}
+
+pub fn warn<M: Into<String>>(message: M) -> Event {
+    Event::new(Level::Warn, message)
+}
+
+pub fn info<M: Into<String>>(message: M) -> Event {
+    Event::new(Level::Info, message)
+}
+
+pub fn debug<M: Into<String>>(message: M) -> Event {
+    Event::new(Level::Debug, message)
+}
+
+/// Durations are logged as fractional seconds.
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// A log line under construction, written by `emit`.
+#[derive(Debug)]
+#[must_use = "events are only written by `emit`"]
+pub struct Event {
+    level: Level,
+    message: String,
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Event {
+    fn new<M: Into<String>>(level: Level, message: M) -> Event {
+        Event { level, message: message.into(), fields: Vec::new() }
+    }
+
+    pub fn field<V: Into<Value>>(mut self, name: &'static str, value: V) -> Event {
+        self.fields.push((name, value.into()));
+        self
+    }
+
+    /// Writes the event, errors and warnings to stderr and the rest to stdout.
+    pub fn emit(self) {
+        if !enabled(self.level) {
+            return;
+        }
+
+        let line = {
+            let channel = CHANNEL.lock().unwrap();
+            match JSON.load(Ordering::SeqCst) {
+                true => self.to_json(&timestamp(SystemTime::now()), &channel),
+                false => self.to_text(&timestamp(SystemTime::now()), &channel),
+            }
+        };
+
+        let _ = match self.level {
+            Level::Error | Level::Warn => writeln!(io::stderr(), "{}", line),
+            Level::Info | Level::Debug => writeln!(io::stdout(), "{}", line),
+        };
+    }
+
+    fn to_json(&self, timestamp: &str, channel: &str) -> String {
+        let mut object = Map::new();
+        object.insert("ts".to_string(), Value::from(timestamp));
+        object.insert("level".to_string(), Value::from(self.level.name()));
+        object.insert("message".to_string(), Value::from(self.message.as_str()));
+        object.insert("channel".to_string(), Value::from(channel));
+        for (name, value) in &self.fields {
+            object.insert(name.to_string(), value.clone());
+        }
+        Value::Object(object).to_string()
+    }
+
+    fn to_text(&self, timestamp: &str, channel: &str) -> String {
+        let mut line = format!(
+            "{} {:5} {} channel={}",
+            timestamp, self.level.name().to_uppercase(), self.message, text_value(&Value::from(channel)));
+        for (name, value) in &self.fields {
+            line.push_str(&format!(" {}={}", name, text_value(value)));
+        }
+        line
+    }
+}
+
+/// Strings are quoted only when they would not read as a single word.
+fn text_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') =>
+            s.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+/// RFC 3339 UTC timestamp with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, since_epoch.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+            "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn event_render_test() {
+        let event = info("task done")
+            .field("task", "Zm9v")
+            .field("exit_code", 0)
+            .field("line", "hello world");
+
+        assert_eq!(
+            event.to_text("2024-02-29T12:34:56.789Z", "foochan"),
+            "2024-02-29T12:34:56.789Z INFO  task done channel=foochan task=Zm9v exit_code=0 line=\"hello world\"");
+        assert_eq!(
+            event.to_json("2024-02-29T12:34:56.789Z", "foochan"),
+            "{\"ts\":\"2024-02-29T12:34:56.789Z\",\"level\":\"info\",\"message\":\"task done\",\
+             \"channel\":\"foochan\",\"task\":\"Zm9v\",\"exit_code\":0,\"line\":\"hello world\"}");
+        assert_eq!("warn".parse::<Level>(), Ok(Level::Warn));
+        assert!("loud".parse::<Format>().is_err());
+    }
+}
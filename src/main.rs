@@ -1,37 +1,117 @@
 extern crate postgres;
 extern crate redis;
+#[macro_use]
+extern crate serde_json;
+extern crate signal_hook;
 
+mod admin;
 mod cli;
+mod commands;
+mod control;
 mod dispatcher;
+mod executor;
+#[cfg(test)]
+mod fake_redis;
+mod filter;
+mod health;
+mod http;
+mod log;
+mod metrics;
+mod payload;
+mod persistent;
+mod rate_limit;
+mod registry;
+mod routes;
+mod settings;
+mod sql;
 mod thread_pool;
+mod webhook;
 
 use cli::create_cli_app;
+use admin::Admin;
 use dispatcher::{Dispatcher, Config};
+use settings::Settings;
 use std::process::exit;
 use std::thread;
-use postgres::{TlsMode};
-use postgres::tls::native_tls::NativeTls;
+use signal_hook::iterator::Signals;
 
 fn main() {
     let cli_matches = create_cli_app().get_matches();
-    let config = Config::from_matches(&cli_matches);
+    if let (name, Some(matches)) = cli_matches.subcommand() {
+        exit(commands::run(name, matches));
+    }
+    let config = match Settings::load(&cli_matches).and_then(|s| Config::from_settings(&s)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}.", error);
+            exit(1);
+        }
+    };
+    log::init(&config.db_channel, config.log_level, config.log_format);
     let dispatcher = Dispatcher::from_config(&config);
     let redis_client = redis::Client::open(config.redis_url.as_str()).unwrap();
     let mut _servers: Vec<thread::JoinHandle<()>> = Vec::new();
 
+    // stop claiming new tasks and drain running ones on SIGTERM/SIGINT,
+    // reload the config file and environment on SIGHUP
+    let signals = Signals::new([
+        signal_hook::SIGTERM, signal_hook::SIGINT, signal_hook::SIGHUP]).unwrap();
+    let control = dispatcher.control.clone();
+    let channel = config.db_channel.clone();
+    thread::spawn(move|| {
+        for signal in signals.forever() {
+            if signal != signal_hook::SIGHUP {
+                log::info("shutting down").field("signal", signal).emit();
+                control.request_shutdown();
+                continue;
+            }
+
+            match Settings::load(&cli_matches).and_then(|s| Config::from_settings(&s)) {
+                Ok(config) => {
+                    log::init(&channel, config.log_level, config.log_format);
+                    log::info("reloading configuration").emit();
+                    control.request_reload(config);
+                },
+                Err(error) => {
+                    log::error("keeping current configuration, reload failed")
+                        .field("error", error)
+                        .emit();
+                }
+            }
+        }
+    });
+
+    if let Some(ref addr) = config.metrics_addr {
+        let admin = Admin::new(&dispatcher, redis_client.clone());
+        if let Err(error) = http::serve(addr, move|request| admin.handle(request)) {
+            log::error("failed to listen")
+                .field("addr", addr.as_str())
+                .field("error", error.to_string())
+                .emit();
+            exit(1);
+        }
+        log::info("serving metrics and health checks").field("addr", addr.as_str()).emit();
+    }
+
+    if let Some(ref addr) = config.admin_addr {
+        let admin = Admin::new(&dispatcher, redis_client.clone()).with_api();
+        if let Err(error) = http::serve(addr, move|request| admin.handle(request)) {
+            log::error("failed to listen")
+                .field("addr", addr.as_str())
+                .field("error", error.to_string())
+                .emit();
+            exit(1);
+        }
+        log::info("serving the admin API").field("addr", addr.as_str()).emit();
+    }
+
     if config.producer {
-        let negotiator = NativeTls::new().unwrap();
-        let tls_mode : TlsMode = match config.tls_mode.as_ref() {
-            "prefer" => { TlsMode::Prefer(&negotiator) },
-            "require" => { TlsMode::Require(&negotiator) },
-            _ => { TlsMode::None },
-        };
-        let pg_conn = match postgres::Connection::connect(
-            config.db_url.as_str(), tls_mode
-         ) {
+        let pg_conn = match dispatcher::connect_postgres(&config) {
             Ok(conn) => conn,
             Err(error) => {
-                eprintln!("Failed to connect to the database: {}.", error);
+                log::error("failed to connect to the database")
+                    .field("error", error.to_string())
+                    .emit();
                 exit(1);
             }
         };
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the command duration histogram buckets.
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    pub fn set(&self, value: usize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<usize>,
+    sum: f64,
+    count: usize,
+}
+
+/// Counters exposed in the Prometheus text format on `/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    channel: String,
+    pub notifications_received: Counter,
+    pub notifications_filtered: Counter,
+    pub tasks_started: Counter,
+    pub tasks_succeeded: Counter,
+    pub tasks_failed: Counter,
+    pub tasks_retried: Counter,
+    pub rate_limited: Counter,
+    pub idle_workers: Gauge,
+    pub busy_workers: Gauge,
+    pub redis_reconnects: Counter,
+    pub postgres_reconnects: Counter,
+    exit_codes: Mutex<BTreeMap<String, usize>>,
+    durations: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn new(channel: &str) -> Metrics {
+        Metrics {
+            channel: channel.to_string(),
+            notifications_received: Counter::default(),
+            notifications_filtered: Counter::default(),
+            tasks_started: Counter::default(),
+            tasks_succeeded: Counter::default(),
+            tasks_failed: Counter::default(),
+            tasks_retried: Counter::default(),
+            rate_limited: Counter::default(),
+            idle_workers: Gauge::default(),
+            busy_workers: Gauge::default(),
+            redis_reconnects: Counter::default(),
+            postgres_reconnects: Counter::default(),
+            exit_codes: Mutex::new(BTreeMap::new()),
+            durations: Mutex::new(Histogram {
+                buckets: vec![0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    /// Records a finished command, `None` standing for a kill by a signal.
+    pub fn observe_command(&self, exit_code: Option<&str>, duration: Duration) {
+        let code = exit_code.unwrap_or("signal").to_string();
+        *self.exit_codes.lock().unwrap().entry(code).or_insert(0) += 1;
+
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let mut durations = self.durations.lock().unwrap();
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                durations.buckets[i] += 1;
+            }
+        }
+        durations.sum += seconds;
+        durations.count += 1;
+    }
+
+    /// Renders every metric, along with the given task set sizes and age of
+    /// the oldest pending task, when they could be read.
+    pub fn render(&self, set_sizes: &[(&str, usize)], oldest_pending_age: Option<u64>) -> String {
+        let mut out = String::new();
+        let channel = format!("channel=\"{}\"", self.channel);
+
+        write_counter(&mut out, "notifications_received_total",
+                      "Notifications received from PostgreSQL.",
+                      &channel, self.notifications_received.get());
+        write_counter(&mut out, "notifications_filtered_total",
+                      "Notifications dropped by a filter instead of being queued.",
+                      &channel, self.notifications_filtered.get());
+
+        header(&mut out, "tasks", "gauge", "Tasks in each set.");
+        for &(set, size) in set_sizes {
+            let _ = writeln!(out, "pg_dispatcher_tasks{{{},set=\"{}\"}} {}", channel, set, size);
+        }
+        if let Some(age) = oldest_pending_age {
+            header(&mut out, "oldest_pending_age_seconds", "gauge",
+                   "Seconds the oldest task not running yet has been waiting, 0 when there is none.");
+            let _ = writeln!(out, "pg_dispatcher_oldest_pending_age_seconds{{{}}} {}", channel, age);
+        }
+
+        write_counter(&mut out, "tasks_started_total", "Tasks handed to a worker.",
+                      &channel, self.tasks_started.get());
+        write_counter(&mut out, "tasks_succeeded_total", "Tasks whose command exited with status 0.",
+                      &channel, self.tasks_succeeded.get());
+        write_counter(&mut out, "tasks_failed_total", "Tasks whose command failed.",
+                      &channel, self.tasks_failed.get());
+        write_counter(&mut out, "tasks_retried_total", "Tasks handed back to the pending set.",
+                      &channel, self.tasks_retried.get());
+        write_counter(&mut out, "rate_limited_total", "Claims stopped short by the rate limit.",
+                      &channel, self.rate_limited.get());
+
+        header(&mut out, "task_exit_codes_total", "counter", "Finished tasks by exit code, status code, SQLSTATE or answer status.");
+        for (code, count) in self.exit_codes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out, "pg_dispatcher_task_exit_codes_total{{{},code=\"{}\"}} {}",
+                channel, code, count);
+        }
+
+        header(&mut out, "task_duration_seconds", "histogram", "Command execution time.");
+        {
+            let durations = self.durations.lock().unwrap();
+            for (bound, count) in DURATION_BUCKETS.iter().zip(durations.buckets.iter()) {
+                let _ = writeln!(
+                    out, "pg_dispatcher_task_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    channel, bound, count);
+            }
+            let _ = writeln!(
+                out, "pg_dispatcher_task_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                channel, durations.count);
+            let _ = writeln!(out, "pg_dispatcher_task_duration_seconds_sum{{{}}} {}", channel, durations.sum);
+            let _ = writeln!(out, "pg_dispatcher_task_duration_seconds_count{{{}}} {}", channel, durations.count);
+        }
+
+        header(&mut out, "workers", "gauge", "Workers by state.");
+        let _ = writeln!(out, "pg_dispatcher_workers{{{},state=\"idle\"}} {}", channel, self.idle_workers.get());
+        let _ = writeln!(out, "pg_dispatcher_workers{{{},state=\"busy\"}} {}", channel, self.busy_workers.get());
+
+        write_counter(&mut out, "redis_reconnects_total", "Connections re-established to Redis.",
+                      &channel, self.redis_reconnects.get());
+        write_counter(&mut out, "postgres_reconnects_total", "Connections re-established to PostgreSQL.",
+                      &channel, self.postgres_reconnects.get());
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP pg_dispatcher_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE pg_dispatcher_{} {}", name, kind);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, labels: &str, value: usize) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "pg_dispatcher_{}{{{}}} {}", name, labels, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_test() {
+        let metrics = Metrics::new("foochan");
+        metrics.notifications_received.inc();
+        metrics.observe_command(Some("1"), Duration::from_millis(200));
+        metrics.observe_command(None, Duration::from_secs(20));
+
+        let rendered = metrics.render(&[("pending", 3)], Some(12));
+
+        assert!(rendered.contains("pg_dispatcher_notifications_received_total{channel=\"foochan\"} 1\n"));
+        assert!(rendered.contains("pg_dispatcher_tasks{channel=\"foochan\",set=\"pending\"} 3\n"));
+        assert!(rendered.contains("pg_dispatcher_oldest_pending_age_seconds{channel=\"foochan\"} 12\n"));
+        assert!(rendered.contains("pg_dispatcher_task_exit_codes_total{channel=\"foochan\",code=\"1\"} 1\n"));
+        assert!(rendered.contains("pg_dispatcher_task_exit_codes_total{channel=\"foochan\",code=\"signal\"} 1\n"));
+        assert!(rendered.contains("pg_dispatcher_task_duration_seconds_bucket{channel=\"foochan\",le=\"0.25\"} 1\n"));
+        assert!(rendered.contains("pg_dispatcher_task_duration_seconds_bucket{channel=\"foochan\",le=\"30\"} 2\n"));
+        assert!(rendered.contains("pg_dispatcher_task_duration_seconds_count{channel=\"foochan\"} 2\n"));
+    }
+}
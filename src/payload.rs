@@ -0,0 +1,293 @@
+extern crate serde_json;
+extern crate sha2;
+
+use std::str::FromStr;
+use self::serde_json::Value;
+use self::sha2::{Digest, Sha256};
+
+/// Replaces redacted values.
+const REDACTED: &str = "[REDACTED]";
+
+/// Characters of a payload kept by `--log-payloads=truncated`.
+const TRUNCATE_AT: usize = 64;
+
+/// Numeric timestamps from this one on are taken as milliseconds, as many
+/// seconds being past the year 5000.
+const MILLISECONDS_FROM: f64 = 1e11;
+
+/// Latest timestamp taken, the end of the year 9999.
+const MAX_TIMESTAMP: u64 = 253_402_300_799;
+
+/// How much of a payload makes it into the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogPayloads {
+    None,
+    Truncated,
+    Full,
+}
+
+impl FromStr for LogPayloads {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogPayloads, String> {
+        match s {
+            "none" => Ok(LogPayloads::None),
+            "truncated" => Ok(LogPayloads::Truncated),
+            "full" => Ok(LogPayloads::Full),
+            _ => Err(format!("unknown payload logging mode {}", s)),
+        }
+    }
+}
+
+/// Strips sensitive values from JSON payloads before they are logged or
+/// stored.
+///
+/// Paths are dot separated keys from the root of the payload, e.g.
+/// `user.email`, optionally prefixed with `$.`. Arrays are traversed, so
+/// `items.email` redacts the `email` of every element of `items`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redactor {
+    log_payloads: LogPayloads,
+    paths: Vec<Vec<String>>,
+}
+
+impl Redactor {
+    pub fn new(log_payloads: LogPayloads, paths: &[&str]) -> Redactor {
+        Redactor {
+            log_payloads,
+            paths: paths.iter()
+                .map(|path| split_path(path))
+                .filter(|path| !path.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The payload with every redacted path replaced. Payloads that are not
+    /// JSON objects or arrays are returned unchanged.
+    pub fn redact(&self, payload: &str) -> String {
+        if self.paths.is_empty() {
+            return payload.to_string();
+        }
+        match serde_json::from_str::<Value>(payload) {
+            Ok(mut value) => {
+                for path in &self.paths {
+                    redact_path(&mut value, path);
+                }
+                value.to_string()
+            },
+            Err(_) => payload.to_string(),
+        }
+    }
+
+    /// What may be logged of a payload, if anything.
+    pub fn loggable(&self, payload: &str) -> Option<String> {
+        match self.log_payloads {
+            LogPayloads::None => None,
+            LogPayloads::Full => Some(self.redact(payload)),
+            LogPayloads::Truncated => {
+                let redacted = self.redact(payload);
+                match redacted.char_indices().nth(TRUNCATE_AT) {
+                    Some((end, _)) => Some(format!("{}...", &redacted[..end])),
+                    None => Some(redacted),
+                }
+            },
+        }
+    }
+}
+
+impl Default for Redactor {
+    fn default() -> Redactor {
+        Redactor::new(LogPayloads::None, &[])
+    }
+}
+
+/// Splits a dot separated path, optionally prefixed with `$.`.
+fn split_path(path: &str) -> Vec<String> {
+    match path.trim().trim_start_matches("$.") {
+        "" => Vec::new(),
+        path => path.split('.').map(String::from).collect(),
+    }
+}
+
+/// The JSON value at a dot separated path of a payload, `None` when the
+/// payload is not JSON or has nothing there.
+pub fn json_at(payload: &str, path: &str) -> Option<Value> {
+    let mut value = serde_json::from_str::<Value>(payload).ok()?;
+    for key in split_path(path) {
+        value = match value {
+            Value::Object(mut object) => object.remove(&key)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Like `json_at`, with strings unquoted and null taken as nothing.
+pub fn value_at(payload: &str, path: &str) -> Option<String> {
+    match json_at(payload, path)? {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        value => Some(value.to_string()),
+    }
+}
+
+/// A JSON value as written in a rule, bare words being taken as strings,
+/// e.g. `DELETE` for `"DELETE"`.
+pub fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::from(value))
+}
+
+/// Unix seconds of a timestamp given in seconds, milliseconds or RFC 3339,
+/// e.g. `2024-02-29T12:34:56.789+01:00`, taken as UTC when without an
+/// offset. `None` for anything past the year 9999.
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let s = timestamp.trim();
+    if let Ok(number) = s.parse::<f64>() {
+        if !number.is_finite() || number < 0.0 {
+            return None;
+        }
+        let seconds = match number >= MILLISECONDS_FROM {
+            true => number / 1000.0,
+            false => number,
+        };
+        return Some(seconds as u64).filter(|seconds| *seconds <= MAX_TIMESTAMP);
+    }
+
+    let field = |start: usize, len: usize| -> Option<i64> {
+        let digits = s.get(start..start + len)?;
+        match digits.bytes().all(|b| b.is_ascii_digit()) {
+            true => digits.parse().ok(),
+            false => None,
+        }
+    };
+    let separated = s.get(4..5) == Some("-") && s.get(7..8) == Some("-")
+        && matches!(s.get(10..11), Some("T") | Some("t") | Some(" "))
+        && s.get(13..14) == Some(":") && s.get(16..17) == Some(":");
+    if !separated {
+        return None;
+    }
+    let (year, month, day) = (field(0, 4)?, field(5, 2)?, field(8, 2)?);
+    let (hour, minute, second) = (field(11, 2)?, field(14, 2)?, field(17, 2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // fractions of a second are dropped
+    let offset = match s[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit()) {
+        "" | "Z" | "z" => 0,
+        offset if offset.len() == 6 && offset.get(3..4) == Some(":") => {
+            let seconds = offset.get(1..3)?.parse::<i64>().ok()? * 3600 + offset.get(4..6)?.parse::<i64>().ok()? * 60;
+            match offset.get(0..1)? {
+                "+" => seconds,
+                "-" => -seconds,
+                _ => return None,
+            }
+        },
+        _ => return None,
+    };
+
+    // days since the epoch of a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    match seconds >= 0 {
+        true => Some(seconds as u64),
+        false => None,
+    }
+}
+
+fn redact_path(value: &mut Value, path: &[String]) {
+    match *value {
+        Value::Array(ref mut items) => {
+            for item in items {
+                redact_path(item, path);
+            }
+        },
+        Value::Object(ref mut object) => {
+            if path.len() == 1 {
+                if let Some(field) = object.get_mut(&path[0]) {
+                    *field = Value::from(REDACTED);
+                }
+            } else if let Some(field) = object.get_mut(&path[0]) {
+                redact_path(field, &path[1..]);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// Short digest of a task key, identifying a task in the logs without
+/// revealing its payload.
+pub fn task_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())[..6].iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_test() {
+        let redactor = Redactor::new(LogPayloads::Full, &["password", "$.user.email", "items.secret"]);
+
+        assert_eq!(
+            redactor.redact(
+                r#"{"password":"hunter2","user":{"email":"a@b.c","id":1},"items":[{"secret":1},{"other":2}]}"#),
+            r#"{"password":"[REDACTED]","user":{"email":"[REDACTED]","id":1},"items":[{"secret":"[REDACTED]"},{"other":2}]}"#);
+        assert_eq!(redactor.redact("not json"), "not json");
+    }
+
+    #[test]
+    fn loggable_test() {
+        let payload = format!("{{\"password\":\"x\",\"data\":\"{}\"}}", "a".repeat(100));
+
+        assert_eq!(Redactor::default().loggable(&payload), None);
+        assert_eq!(
+            Redactor::new(LogPayloads::Truncated, &["password"]).loggable(&payload).unwrap(),
+            format!("{{\"password\":\"[REDACTED]\",\"data\":\"{}...", "a".repeat(31)));
+        assert_eq!(task_id("Zm9v").len(), 12);
+    }
+
+    #[test]
+    fn value_at_test() {
+        let payload = r#"{"account_id":42,"user":{"email":"a@b.c","org":null}}"#;
+
+        assert_eq!(value_at(payload, "$.account_id"), Some("42".to_string()));
+        assert_eq!(value_at(payload, "user.email"), Some("a@b.c".to_string()));
+        assert_eq!(value_at(payload, "user.org"), None);
+        assert_eq!(value_at(payload, "account_id.id"), None);
+        assert_eq!(value_at("not json", "account_id"), None);
+    }
+
+    #[test]
+    fn parse_value_test() {
+        assert_eq!(parse_value("DELETE"), Value::from("DELETE"));
+        assert_eq!(parse_value(r#""DELETE""#), Value::from("DELETE"));
+        assert_eq!(parse_value("3"), Value::from(3));
+        assert_eq!(parse_value("null"), Value::Null);
+    }
+
+    #[test]
+    fn parse_timestamp_test() {
+        assert_eq!(parse_timestamp("1709210096"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("1709210096.789"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("2024-02-29T12:34:56Z"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("2024-02-29T13:34:56.789012+01:00"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("2024-02-29 12:34:56"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_timestamp("tomorrow"), None);
+        assert_eq!(parse_timestamp("1709210096789"), Some(1_709_210_096));
+        assert_eq!(parse_timestamp("inf"), None);
+        assert_eq!(parse_timestamp("NaN"), None);
+        assert_eq!(parse_timestamp("1e300"), None);
+    }
+}
@@ -0,0 +1,403 @@
+extern crate serde_json;
+
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{self, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use self::serde_json::Value;
+use executor::{CHILD_POLL_INTERVAL, Executor, Outcome, Output, Task};
+use log;
+
+/// How long a child closed for good gets to exit before being killed.
+const EXIT_TIMEOUT: u64 = 1;
+
+/// A long-lived command running one task after another.
+#[derive(Debug)]
+struct Child {
+    process: process::Child,
+    stdin: Option<ChildStdin>,
+    /// Lines written on the standard output, disconnected when it closes.
+    lines: mpsc::Receiver<String>,
+    tasks: usize,
+    /// Id and attempt of the task running, for the lines written on the
+    /// standard error.
+    task: Arc<Mutex<Option<(String, u64)>>>,
+}
+
+impl Child {
+    fn spawn(command_vector: &[OsString]) -> io::Result<Child> {
+        let mut process = Command::new(&command_vector[0])
+            .args(&command_vector[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let pid = process.id();
+
+        let (sender, lines) = mpsc::channel();
+        let stdout = process.stdout.take().unwrap();
+        thread::spawn(move|| {
+            for line in BufReader::new(stdout).lines() {
+                match line.map(|line| sender.send(line)) {
+                    Ok(Ok(())) => {},
+                    _ => break,
+                }
+            }
+        });
+        let stderr = process.stderr.take().unwrap();
+        let task: Arc<Mutex<Option<(String, u64)>>> = Arc::new(Mutex::new(None));
+        let running = task.clone();
+        thread::spawn(move|| {
+            for line in BufReader::new(stderr).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let mut event = log::info("command output").field("pid", pid);
+                if let Some((ref id, attempt)) = *running.lock().unwrap() {
+                    event = event.field("task", id.as_str()).field("attempt", attempt);
+                }
+                event.field("stream", "stderr").field("line", line).emit();
+            }
+        });
+
+        log::info("started persistent command")
+            .field("pid", pid)
+            .field("program", command_vector[0].to_string_lossy().as_ref())
+            .emit();
+        Ok(Child { stdin: process.stdin.take(), process, lines, tasks: 0, task })
+    }
+
+    /// Closes the standard input for the child to exit, killing it if it
+    /// does not in time.
+    fn finish(&mut self) -> Option<ExitStatus> {
+        self.stdin.take();
+        let deadline = Instant::now() + Duration::from_secs(EXIT_TIMEOUT);
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = self.process.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(CHILD_POLL_INTERVAL));
+        }
+        let _ = self.process.kill();
+        self.process.wait().ok()
+    }
+
+    fn kill(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Children of a `PersistentExecutor` not running a task, and how many are
+/// running.
+#[derive(Debug, Default)]
+struct Children {
+    idle: Vec<Child>,
+    open: usize,
+}
+
+/// Runs tasks on long-lived children of a command, sparing interpreted
+/// handlers a boot for every task.
+///
+/// Each task is written to a child as a line of JSON, `{"id": ..., "payload": ...}`,
+/// the payload being a string, and the child answers with a line
+/// `{"id": ..., "status": "ok" | "retry" | "error"}`, optionally with an
+/// `output`. Answers to other tasks are discarded and any other line it
+/// writes is logged. Children are started when needed, up to `max_children`,
+/// and replaced when they exit or have run `max_tasks` tasks.
+#[derive(Debug, Clone)]
+pub struct PersistentExecutor {
+    command_vector: Vec<OsString>,
+    /// Children running at once, 0 for one per busy worker.
+    max_children: usize,
+    /// Tasks a child runs before being replaced, 0 for no limit.
+    max_tasks: usize,
+    /// How long a child has to answer before being killed.
+    timeout: Option<Duration>,
+    children: Arc<(Mutex<Children>, Condvar)>,
+}
+
+impl PersistentExecutor {
+    pub fn new(
+        command_vector: Vec<OsString>, max_children: usize,
+        max_tasks: usize, timeout: Option<Duration>) -> PersistentExecutor {
+        PersistentExecutor {
+            command_vector, max_children, max_tasks, timeout,
+            children: Arc::new((Mutex::new(Children::default()), Condvar::new())),
+        }
+    }
+
+    /// Takes an idle child, or starts one when fewer than `max_children` run,
+    /// waiting for one to be given back otherwise. `None` when aborted while
+    /// waiting.
+    fn checkout(&self, abort: &AtomicBool) -> Option<io::Result<Child>> {
+        let (ref lock, ref released) = *self.children;
+        let mut children = lock.lock().unwrap();
+        loop {
+            // children may have exited since they went idle
+            let idle = children.idle.len();
+            children.idle.retain_mut(|child| child.process.try_wait().ok().flatten().is_none());
+            children.open -= idle - children.idle.len();
+            if let Some(child) = children.idle.pop() {
+                return Some(Ok(child));
+            }
+            if self.max_children == 0 || children.open < self.max_children {
+                children.open += 1;
+                break;
+            }
+            if abort.load(Ordering::SeqCst) {
+                return None;
+            }
+            children = released.wait_timeout(children, Duration::from_millis(CHILD_POLL_INTERVAL)).unwrap().0;
+        }
+        drop(children);
+
+        let spawned = Child::spawn(&self.command_vector);
+        if spawned.is_err() {
+            self.checkin(None);
+        }
+        Some(spawned)
+    }
+
+    /// Gives a child back, `None` for one that exited or was replaced.
+    fn checkin(&self, child: Option<Child>) {
+        let (ref lock, ref released) = *self.children;
+        let mut children = lock.lock().unwrap();
+        match child {
+            Some(child) => children.idle.push(child),
+            None => children.open -= 1,
+        }
+        released.notify_one();
+    }
+
+    /// Waits for the answer of the child to a task.
+    fn answer(&self, child: &mut Child, task: &Task, abort: &AtomicBool) -> Outcome {
+        let id = task.id();
+        let started_at = Instant::now();
+        loop {
+            if abort.load(Ordering::SeqCst) {
+                child.kill();
+                log::warn("command aborted")
+                    .field("worker", task.worker)
+                    .field("task", id)
+                    .field("attempt", task.attempt)
+                    .field("pid", child.process.id())
+                    .emit();
+                return Outcome::Retryable(Output::default());
+            }
+            if self.timeout.is_some_and(|timeout| started_at.elapsed() >= timeout) {
+                child.kill();
+                log::warn("persistent command timed out, retrying later")
+                    .field("worker", task.worker)
+                    .field("task", id)
+                    .field("attempt", task.attempt)
+                    .field("pid", child.process.id())
+                    .emit();
+                return Outcome::Timeout(Output::default());
+            }
+
+            let line = match child.lines.recv_timeout(Duration::from_millis(CHILD_POLL_INTERVAL)) {
+                Ok(line) => line,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let status = child.finish();
+                    log::warn("persistent command exited, retrying later")
+                        .field("worker", task.worker)
+                        .field("task", id)
+                        .field("attempt", task.attempt)
+                        .field("pid", child.process.id())
+                        .field("exit_code", status.and_then(|status| status.code()))
+                        .emit();
+                    return Outcome::Retryable(Output::default());
+                }
+            };
+            match parse_answer(&line) {
+                Some((ref answered, _)) if *answered != id => log::debug("discarded answer to another task")
+                    .field("worker", task.worker)
+                    .field("task", id.as_str())
+                    .field("attempt", task.attempt)
+                    .field("answered", answered.as_str())
+                    .emit(),
+                Some((_, outcome)) => {
+                    let event = match outcome {
+                        Outcome::Success(_) => log::info("command succeeded"),
+                        _ => log::warn("command failed"),
+                    };
+                    event
+                        .field("worker", task.worker)
+                        .field("task", id)
+                        .field("attempt", task.attempt)
+                        .field("pid", child.process.id())
+                        .field("status", outcome.output().code.clone())
+                        .field("duration", log::seconds(started_at.elapsed()))
+                        .emit();
+                    return outcome;
+                },
+                None => log::info("command output")
+                    .field("worker", task.worker)
+                    .field("task", id.as_str())
+                    .field("attempt", task.attempt)
+                    .field("stream", "stdout")
+                    .field("line", line)
+                    .emit(),
+            }
+        }
+    }
+}
+
+impl Executor for PersistentExecutor {
+    /// Kills the child running the task when aborted.
+    fn execute(&self, task: &Task, abort: &AtomicBool) -> Outcome {
+        let mut child = match self.checkout(abort) {
+            Some(Ok(child)) => child,
+            // aborted while waiting for a child
+            None => return Outcome::Retryable(Output::default()),
+            Some(Err(_)) => {
+                log::error("couldn't execute program")
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("program", self.command_vector[0].to_string_lossy().as_ref())
+                    .emit();
+                return Outcome::Retryable(Output::default());
+            }
+        };
+
+        child.tasks += 1;
+        *child.task.lock().unwrap() = Some((task.id(), task.attempt));
+        let frame = json!({ "id": task.id(), "payload": task.payload });
+        let sent = match child.stdin {
+            Some(ref mut stdin) => writeln!(stdin, "{}", frame).and_then(|_| stdin.flush()),
+            None => Ok(()),
+        };
+        let outcome = match sent {
+            Ok(()) => self.answer(&mut child, task, abort),
+            Err(_) => {
+                log::warn("persistent command exited, retrying later")
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("pid", child.process.id())
+                    .field("exit_code", child.finish().and_then(|status| status.code()))
+                    .emit();
+                self.checkin(None);
+                return Outcome::Retryable(Output::default());
+            }
+        };
+        *child.task.lock().unwrap() = None;
+
+        let exited = child.process.try_wait().ok().and_then(|status| status).is_some();
+        if self.max_tasks > 0 && child.tasks >= self.max_tasks && !exited {
+            log::info("replacing persistent command")
+                .field("pid", child.process.id())
+                .field("tasks", child.tasks)
+                .emit();
+            drop(child);
+            self.checkin(None);
+        } else if exited {
+            self.checkin(None);
+        } else {
+            self.checkin(Some(child));
+        }
+        outcome
+    }
+}
+
+impl PartialEq for PersistentExecutor {
+    fn eq(&self, other: &PersistentExecutor) -> bool {
+        self.command_vector == other.command_vector && self.max_children == other.max_children
+            && self.max_tasks == other.max_tasks && self.timeout == other.timeout
+    }
+}
+
+/// The id of the task a line of a child answers and the outcome it answers
+/// with, `None` when it is not an answer.
+fn parse_answer(line: &str) -> Option<(String, Outcome)> {
+    let answer = match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(answer)) => answer,
+        _ => return None,
+    };
+    let id = answer.get("id").and_then(Value::as_str)?.to_string();
+    let status = answer.get("status").and_then(Value::as_str).unwrap_or("error").to_string();
+    let output = Output {
+        details: json!({ "status": status, "output": answer.get("output") }),
+        code: Some(status),
+    };
+    Some((id, match output.code.as_deref() {
+        Some("ok") => Outcome::Success(output),
+        Some("retry") => Outcome::Retryable(output),
+        _ => Outcome::Permanent(output),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persistent_parse_answer_test() {
+        assert!(matches!(parse_answer(r#"{"id":"ab12","status":"ok"}"#), Some((_, Outcome::Success(_)))));
+        assert!(matches!(parse_answer(r#"{"id":"ab12","status":"retry"}"#), Some((_, Outcome::Retryable(_)))));
+        assert_eq!(
+            parse_answer(r#"{"id":"ab12","status":"error","output":"no such user"}"#)
+                .map(|(id, outcome)| (id, outcome.output().details.clone())),
+            Some(("ab12".to_string(), json!({ "status": "error", "output": "no such user" }))));
+        assert_eq!(parse_answer(r#"{"status":"ok"}"#), None);
+        assert_eq!(parse_answer("loading models..."), None);
+    }
+
+    #[test]
+    fn persistent_executor_test() {
+        // answers every task and exits after two of them
+        let script = r#"for i in 1 2; do read -r line; id=${line#*\"id\":\"}; echo "{\"id\":\"${id%%\"*}\",\"status\":\"ok\"}"; done"#;
+        let executor = PersistentExecutor::new(
+            vec![OsString::from("sh"), OsString::from("-c"), OsString::from(script)], 0, 0, None);
+        let abort = AtomicBool::new(false);
+        let task = |payload: &str| Task { payload: payload.to_string(), key: payload.to_string(), worker: 0, attempt: 1 };
+
+        assert!(matches!(executor.execute(&task("foo"), &abort), Outcome::Success(_)));
+        assert_eq!(executor.children.0.lock().unwrap().idle.len(), 1);
+        assert!(matches!(executor.execute(&task("bar"), &abort), Outcome::Success(_)));
+        // replaced after exiting
+        while !executor.children.0.lock().unwrap().idle.iter_mut()
+            .all(|child| child.process.try_wait().unwrap().is_some()) {
+            thread::sleep(Duration::from_millis(CHILD_POLL_INTERVAL));
+        }
+        assert!(matches!(executor.execute(&task("baz"), &abort), Outcome::Success(_)));
+    }
+
+    #[test]
+    fn persistent_executor_children_test() {
+        // answers another task before the one it was given
+        let script = r#"while read -r line; do id=${line#*\"id\":\"}; sleep 0.2; echo '{"id":"other","status":"error"}'; echo "{\"id\":\"${id%%\"*}\",\"status\":\"ok\"}"; done"#;
+        let executor = PersistentExecutor::new(
+            vec![OsString::from("sh"), OsString::from("-c"), OsString::from(script)], 1, 0, None);
+        let task = |payload: &str| Task { payload: payload.to_string(), key: payload.to_string(), worker: 0, attempt: 1 };
+
+        // two workers share the one child
+        let other = executor.clone();
+        let worker = thread::spawn(move|| other.execute(&task("foo"), &AtomicBool::new(false)));
+        assert!(matches!(executor.execute(&task("bar"), &AtomicBool::new(false)), Outcome::Success(_)));
+        assert!(matches!(worker.join().unwrap(), Outcome::Success(_)));
+        assert_eq!(executor.children.0.lock().unwrap().open, 1);
+
+        // a worker waiting for a child gives up when aborted
+        let busy = executor.clone();
+        let worker = thread::spawn(move|| busy.execute(&task("baz"), &AtomicBool::new(false)));
+        while !executor.children.0.lock().unwrap().idle.is_empty() {
+            thread::sleep(Duration::from_millis(CHILD_POLL_INTERVAL));
+        }
+        assert_eq!(executor.execute(&task("qux"), &AtomicBool::new(true)), Outcome::Retryable(Output::default()));
+        assert!(matches!(worker.join().unwrap(), Outcome::Success(_)));
+    }
+}
@@ -0,0 +1,191 @@
+extern crate redis;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use redis::PipelineCommands;
+
+/// Commands allowed to start per period, e.g. `50/s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub count: u32,
+    /// Period in seconds.
+    pub period: u64,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let invalid = || format!("invalid rate limit {}, expected e.g. 50/s, 100/m or 1000/h", s);
+        let (count, unit) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let count = count.trim().parse::<u32>().ok().filter(|count| *count > 0).ok_or_else(invalid)?;
+        let period = match unit.trim() {
+            "s" | "sec" | "second" => 1,
+            "m" | "min" | "minute" => 60,
+            "h" | "hour" => 3600,
+            _ => return Err(invalid()),
+        };
+        Ok(Rate { count, period })
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.period {
+            3600 => write!(f, "{}/h", self.count),
+            60 => write!(f, "{}/m", self.count),
+            _ => write!(f, "{}/s", self.count),
+        }
+    }
+}
+
+/// Tokens left in a bucket holding up to `Rate::count` of them, refilled
+/// evenly over the period.
+#[derive(Debug, Clone, PartialEq)]
+struct Bucket {
+    tokens: f64,
+    /// Unix time in milliseconds of the last refill.
+    updated_at: u64,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: u64) -> Bucket {
+        Bucket { tokens: f64::from(rate.count), updated_at: now }
+    }
+
+    fn refill(&mut self, rate: Rate, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at);
+        let refilled = (elapsed * u64::from(rate.count)) as f64 / (rate.period * 1000) as f64;
+        self.tokens = (self.tokens + refilled).min(f64::from(rate.count));
+        self.updated_at = self.updated_at.max(now);
+    }
+
+    fn take(&mut self, rate: Rate, now: u64) -> bool {
+        self.refill(rate, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Gives back a token taken for nothing.
+    fn put_back(&mut self, rate: Rate, now: u64) {
+        self.refill(rate, now);
+        self.tokens = (self.tokens + 1.0).min(f64::from(rate.count));
+    }
+
+    /// Time until the next token.
+    fn wait(&self, rate: Rate, now: u64) -> Duration {
+        let mut bucket = self.clone();
+        bucket.refill(rate, now);
+        let missing = (1.0 - bucket.tokens).max(0.0);
+        Duration::from_millis((missing * (rate.period * 1000) as f64 / f64::from(rate.count)).ceil() as u64)
+    }
+}
+
+/// Token bucket limiting how fast commands start, either for a single
+/// consumer or shared through Redis by every consumer of a channel.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: Rate,
+    /// The local bucket, or the last seen state of the shared one.
+    bucket: Bucket,
+    /// Redis hash holding the shared bucket.
+    shared_key: Option<String>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate, shared_key: Option<String>) -> RateLimiter {
+        RateLimiter { rate, bucket: Bucket::full(rate, now_millis()), shared_key }
+    }
+
+    /// Takes a token for a command to start, false when there is none left.
+    pub fn try_acquire(&mut self, redis_conn: &redis::Connection) -> redis::RedisResult<bool> {
+        self.update(redis_conn, |bucket, rate, now| bucket.take(rate, now))
+    }
+
+    /// Gives back the token of a command that did not start after all.
+    pub fn release(&mut self, redis_conn: &redis::Connection) -> redis::RedisResult<()> {
+        self.update(redis_conn, |bucket, rate, now| bucket.put_back(rate, now))
+    }
+
+    /// Applies `change` to the local bucket, or atomically to the shared one.
+    fn update<T, F>(&mut self, redis_conn: &redis::Connection, change: F) -> redis::RedisResult<T>
+        where T: Default, F: Fn(&mut Bucket, Rate, u64) -> T {
+        let key = match self.shared_key {
+            Some(ref key) => key.clone(),
+            None => return Ok(change(&mut self.bucket, self.rate, now_millis())),
+        };
+
+        let rate = self.rate;
+        let mut result = T::default();
+        let mut bucket = self.bucket.clone();
+        let _ : () = redis::transaction(redis_conn, &[key.as_str()], |pipe| {
+            let now = now_millis();
+            let (tokens, updated_at): (Option<f64>, Option<u64>) =
+                redis::cmd("HMGET").arg(&key).arg("tokens").arg("updated_at").query(redis_conn)?;
+            bucket = match (tokens, updated_at) {
+                (Some(tokens), Some(updated_at)) => Bucket { tokens, updated_at },
+                _ => Bucket::full(rate, now),
+            };
+            result = change(&mut bucket, rate, now);
+
+            // a bucket left alone for a period is full again anyway
+            pipe.hset_multiple(&key, &[
+                    ("tokens", bucket.tokens.to_string()),
+                    ("updated_at", bucket.updated_at.to_string())]).ignore()
+                .pexpire(&key, rate.period as usize * 1000).ignore()
+                .query(redis_conn)
+        })?;
+
+        self.bucket = bucket;
+        Ok(result)
+    }
+
+    /// Time until the next token, as of the last `try_acquire`.
+    pub fn wait(&self) -> Duration {
+        self.bucket.wait(self.rate, now_millis()).max(Duration::from_millis(1))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_parse_test() {
+        assert_eq!("50/s".parse::<Rate>(), Ok(Rate { count: 50, period: 1 }));
+        assert_eq!("100/min".parse::<Rate>(), Ok(Rate { count: 100, period: 60 }));
+        assert_eq!("1000/h".parse::<Rate>().unwrap().to_string(), "1000/h");
+        assert!("50".parse::<Rate>().is_err());
+        assert!("0/s".parse::<Rate>().is_err());
+        assert!("50/d".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn bucket_test() {
+        let rate = Rate { count: 2, period: 1 };
+        let mut bucket = Bucket::full(rate, 10_000);
+
+        assert!(bucket.take(rate, 10_000));
+        assert!(bucket.take(rate, 10_000));
+        assert!(!bucket.take(rate, 10_100));
+        assert_eq!(bucket.wait(rate, 10_100), Duration::from_millis(400));
+        assert!(bucket.take(rate, 10_500));
+        assert!(!bucket.take(rate, 10_500));
+        bucket.put_back(rate, 10_500);
+        assert!(bucket.take(rate, 10_500));
+
+        // refilled up to the capacity only
+        bucket.refill(rate, 20_000);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+}
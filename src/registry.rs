@@ -0,0 +1,146 @@
+extern crate redis;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+use dispatcher::{unix_time, RedisKeys};
+use redis::{Commands, PipelineCommands};
+
+/// Seconds between heartbeats of a consumer.
+pub const HEARTBEAT_INTERVAL: u64 = 10;
+
+/// Seconds after its last heartbeat a consumer's registration expires.
+pub const HEARTBEAT_TTL: usize = 30;
+
+/// A consumer as registered in Redis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerInfo {
+    pub id: String,
+    pub host: String,
+    pub pid: u32,
+    pub version: String,
+    pub channel: String,
+    pub group: String,
+    pub workers: usize,
+    pub started_at: u64,
+    pub heartbeat_at: u64,
+    /// Keys of the tasks the consumer is running.
+    pub in_flight: Vec<String>,
+}
+
+impl ConsumerInfo {
+    /// This process, as a consumer of `channel`.
+    pub fn current(channel: &str, group: &str, workers: usize) -> ConsumerInfo {
+        let host = hostname();
+        let pid = process::id();
+        let now = unix_time();
+
+        ConsumerInfo {
+            id: format!("{}-{}", host, pid),
+            host,
+            pid,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            channel: channel.to_string(),
+            group: group.to_string(),
+            workers,
+            started_at: now,
+            heartbeat_at: now,
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("host", self.host.clone()),
+            ("pid", self.pid.to_string()),
+            ("version", self.version.clone()),
+            ("channel", self.channel.clone()),
+            ("group", self.group.clone()),
+            ("workers", self.workers.to_string()),
+            ("started_at", self.started_at.to_string()),
+            ("heartbeat_at", self.heartbeat_at.to_string()),
+        ]
+    }
+
+    fn from_fields(id: &str, fields: &HashMap<String, String>, in_flight: Vec<String>) -> Option<ConsumerInfo> {
+        let field = |name: &str| fields.get(name).cloned();
+        Some(ConsumerInfo {
+            id: id.to_string(),
+            host: field("host")?,
+            pid: field("pid")?.parse().ok()?,
+            version: field("version")?,
+            channel: field("channel")?,
+            group: field("group")?,
+            workers: field("workers")?.parse().ok()?,
+            started_at: field("started_at")?.parse().ok()?,
+            heartbeat_at: field("heartbeat_at")?.parse().ok()?,
+            in_flight,
+        })
+    }
+}
+
+/// Registers the consumer, or refreshes its registration, which expires
+/// unless refreshed again within `HEARTBEAT_TTL` seconds.
+pub fn heartbeat(redis_conn: &redis::Connection, keys: &RedisKeys, consumer: &ConsumerInfo) -> redis::RedisResult<()> {
+    let key = keys.consumer(&consumer.id);
+    redis::pipe().atomic()
+        .hset_multiple(&key, &consumer.fields()).ignore()
+        .expire(&key, HEARTBEAT_TTL).ignore()
+        .expire(keys.consumer_in_flight(&consumer.id), HEARTBEAT_TTL).ignore()
+        .zadd(&keys.consumers, &consumer.id, consumer.heartbeat_at).ignore()
+        .query(redis_conn)
+}
+
+pub fn deregister(redis_conn: &redis::Connection, keys: &RedisKeys, id: &str) -> redis::RedisResult<()> {
+    redis::pipe().atomic()
+        .del(keys.consumer(id)).ignore()
+        .del(keys.consumer_in_flight(id)).ignore()
+        .zrem(&keys.consumers, id).ignore()
+        .query(redis_conn)
+}
+
+/// Consumers whose registration has not expired, forgetting the others.
+pub fn live_consumers(redis_conn: &redis::Connection, keys: &RedisKeys) -> redis::RedisResult<Vec<ConsumerInfo>> {
+    let ids: Vec<String> = redis_conn.zrange(keys.consumers.as_str(), 0, -1)?;
+    let mut consumers = Vec::new();
+
+    for id in ids {
+        let fields: HashMap<String, String> = redis_conn.hgetall(keys.consumer(&id))?;
+        let in_flight: Vec<String> = redis_conn.smembers(keys.consumer_in_flight(&id))?;
+        match ConsumerInfo::from_fields(&id, &fields, in_flight) {
+            Some(consumer) => consumers.push(consumer),
+            None => redis_conn.zrem(keys.consumers.as_str(), &id)?,
+        }
+    }
+
+    Ok(consumers)
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumer_info_fields_test() {
+        let mut consumer = ConsumerInfo::current("foochan", "default", 4);
+        consumer.in_flight = vec!["Zm9v".to_string()];
+        let fields: HashMap<String, String> = consumer.fields().into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+
+        assert_eq!(consumer.id, format!("{}-{}", consumer.host, process::id()));
+        assert_eq!(
+            ConsumerInfo::from_fields(&consumer.id, &fields, vec!["Zm9v".to_string()]),
+            Some(consumer.clone()));
+        assert_eq!(ConsumerInfo::from_fields(&consumer.id, &HashMap::new(), Vec::new()), None);
+    }
+}
@@ -0,0 +1,139 @@
+extern crate regex;
+extern crate serde_json;
+
+use std::ffi::OsString;
+use std::sync::Arc;
+use self::regex::Regex;
+use self::serde_json::Value;
+use executor::{CommandExecutor, Executor};
+use payload;
+
+/// What a route matches a payload on.
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// The JSON value at a path equals the given one.
+    Field(String, Value),
+    /// The raw payload matches a regex, globs being turned into anchored ones.
+    Pattern(Regex),
+}
+
+/// A command to run the payloads matching a rule with.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// The rule as written, e.g. `$.type == "invoice.paid"`.
+    rule: String,
+    matcher: Matcher,
+    command: Vec<OsString>,
+    executor: Arc<dyn Executor>,
+}
+
+impl Route {
+    /// Parses `<match> => <command>`, where the match is one of
+    /// `$.path == <json value>`, `/<regex>/` or a glob such as `invoice:*`.
+    pub fn parse(route: &str) -> Result<Route, String> {
+        let (rule, command) = route.rsplit_once("=>")
+            .map(|(rule, command)| (rule.trim(), command.trim()))
+            .filter(|&(rule, command)| !rule.is_empty() && !command.is_empty())
+            .ok_or_else(|| format!("invalid route {}, expected <match> => <command>", route))?;
+
+        let matcher = if rule.starts_with("$.") && rule.contains("==") {
+            let (path, value) = rule.split_once("==").unwrap();
+            Matcher::Field(path.trim().to_string(), payload::parse_value(value.trim()))
+        } else if rule.len() > 1 && rule.starts_with('/') && rule.ends_with('/') {
+            Matcher::Pattern(Regex::new(&rule[1..rule.len() - 1])
+                .map_err(|error| format!("invalid route regex {}: {}", rule, error))?)
+        } else {
+            let glob = rule.split('*').map(regex::escape).collect::<Vec<_>>().join(".*");
+            // `*` matches across lines too, e.g. of pretty-printed JSON
+            Matcher::Pattern(Regex::new(&format!("(?s)^{}$", glob)).unwrap())
+        };
+
+        let command: Vec<OsString> = command.split_whitespace().map(OsString::from).collect();
+        Ok(Route {
+            rule: rule.to_string(),
+            matcher,
+            executor: Arc::new(CommandExecutor::new(command.clone())),
+            command,
+        })
+    }
+
+    fn matches(&self, payload: &str) -> bool {
+        match self.matcher {
+            Matcher::Field(ref path, ref value) => payload::json_at(payload, path).as_ref() == Some(value),
+            Matcher::Pattern(ref regex) => regex.is_match(payload),
+        }
+    }
+}
+
+impl PartialEq for Route {
+    fn eq(&self, other: &Route) -> bool {
+        self.rule == other.rule && self.command == other.command
+    }
+}
+
+/// Picks the executor of the first route matching a payload, or the default
+/// one when none does.
+#[derive(Debug, Clone)]
+pub struct Routes {
+    routes: Vec<Route>,
+    default: Arc<dyn Executor>,
+}
+
+impl Routes {
+    pub fn new(routes: Vec<Route>, default: Arc<dyn Executor>) -> Routes {
+        Routes { routes, default }
+    }
+
+    pub fn executor_for(&self, payload: &str) -> Arc<dyn Executor> {
+        self.route_for(payload)
+            .map_or_else(|| self.default.clone(), |route| route.executor.clone())
+    }
+
+    /// The first route matching a payload, if any.
+    fn route_for(&self, payload: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(payload))
+    }
+}
+
+/// A single command for every payload.
+impl From<Vec<OsString>> for Routes {
+    fn from(command_vector: Vec<OsString>) -> Routes {
+        Routes::new(Vec::new(), Arc::new(CommandExecutor::new(command_vector)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_route_for_test() {
+        let rules = [
+            r#"$.type == "invoice.paid" => ./paid.sh --notify"#,
+            "$.attempt == 3 => ./last-attempt.sh",
+            "/^order:[0-9]+$/ => ./order.sh",
+            "invoice:* => ./invoice.sh",
+        ];
+        let routes = Routes::new(rules.iter().map(|rule| Route::parse(rule).unwrap()).collect(),
+                                 Arc::new(CommandExecutor::new(vec![OsString::from("cat")])));
+        let route = |rule: &str| Some(Route::parse(rule).unwrap());
+
+        assert_eq!(routes.route_for(r#"{"type":"invoice.paid"}"#).cloned(), route(rules[0]));
+        assert_eq!(routes.route_for(r#"{"attempt":3}"#).cloned(), route(rules[1]));
+        assert_eq!(routes.route_for("order:42").cloned(), route(rules[2]));
+        assert_eq!(routes.route_for("invoice:42").cloned(), route(rules[3]));
+        assert_eq!(routes.route_for("order:42:x"), None);
+        assert_eq!(routes.route_for(r#"{"type":"invoice.sent"}"#), None);
+    }
+
+    #[test]
+    fn route_parse_test() {
+        assert!(Route::parse("invoice:*").is_err());
+        assert!(Route::parse(" => ./invoice.sh").is_err());
+        assert!(Route::parse("/(/ => ./invoice.sh").is_err());
+        assert!(Route::parse("$.type == invoice => ./invoice.sh").unwrap().matches(r#"{"type":"invoice"}"#));
+        let glob = Route::parse(r#"{*"type": "invoice*"*} => ./invoice.sh"#).unwrap();
+        assert!(glob.matches("{\n  \"type\": \"invoice.paid\",\n  \"id\": 42\n}"));
+        assert!(!glob.matches("{\n  \"type\": \"refund\"\n}"));
+    }
+}
@@ -0,0 +1,147 @@
+extern crate clap;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+/// Prefix of the environment variables that can provide option values,
+/// e.g. `PG_DISPATCHER_WORKERS` for `--workers`.
+const ENV_PREFIX: &str = "PG_DISPATCHER_";
+
+/// Option values looked up, in order, on the command line, in the environment
+/// and in the file given by `--config`.
+///
+/// The config file holds one `option = value` line per option, using the long
+/// option names without dashes in front. Lines starting with `#` are ignored.
+#[derive(Debug, Clone)]
+pub struct Settings<'a> {
+    matches: clap::ArgMatches<'a>,
+    file: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Settings<'a> {
+    /// Reads the environment and config file on top of the command line.
+    pub fn load(matches: &clap::ArgMatches<'a>) -> Result<Settings<'a>, String> {
+        let mut settings = Settings::from_matches(matches);
+
+        if let Some(path) = settings.value_of("config") {
+            let mut contents = String::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|error| format!("failed to read config file {}: {}", path, error))?;
+            settings.file = parse_config_file(&contents)?;
+        }
+
+        Ok(settings)
+    }
+
+    /// Settings made of the command line alone.
+    pub fn from_matches(matches: &clap::ArgMatches<'a>) -> Settings<'a> {
+        Settings { matches: matches.clone(), file: HashMap::new() }
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.matches.value_of(name) {
+            return Some(value.to_string());
+        }
+        if let Ok(value) = env::var(env_name(name)) {
+            return Some(value);
+        }
+        self.file.get(name).and_then(|values| values.last().cloned())
+    }
+
+    /// Every value of an option that can be given more than once, from the
+    /// first place it is set in. The environment gives one value per line.
+    pub fn values_of(&self, name: &str) -> Vec<String> {
+        if let Some(values) = self.matches.values_of(name) {
+            return values.map(String::from).collect();
+        }
+        if let Ok(value) = env::var(env_name(name)) {
+            return value.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect();
+        }
+        self.file.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Whether a flag is given on the command line, or set to `true`, `yes`
+    /// or `1` in the environment or the config file.
+    pub fn flag(&self, name: &str) -> bool {
+        self.matches.is_present(name) || self.value_of(name)
+            .is_some_and(|value| ["true", "yes", "1"].contains(&value.trim().to_lowercase().as_str()))
+    }
+
+    /// Like `value_of`, failing when the option is not set anywhere.
+    pub fn require(&self, name: &str) -> Result<String, String> {
+        self.value_of(name)
+            .ok_or_else(|| format!("missing required option --{}", name))
+    }
+}
+
+fn env_name(name: &str) -> String {
+    format!("{}{}", ENV_PREFIX, name.to_uppercase().replace("-", "_"))
+}
+
+fn parse_config_file(contents: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) => value.trim().trim_matches('"'),
+            None => return Err(format!("invalid config line {}: {}", number + 1, line)),
+        };
+
+        values.entry(name.to_string())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cli;
+
+    #[test]
+    fn parse_config_file_test() {
+        let values = parse_config_file("
+            # comment
+            exec = sh test.sh
+            workers=5
+            channel = \"foochan\"
+        ").unwrap();
+
+        assert_eq!(values["exec"], vec!["sh test.sh"]);
+        assert_eq!(values["workers"], vec!["5"]);
+        assert_eq!(values["channel"], vec!["foochan"]);
+        assert!(parse_config_file("workers").is_err());
+    }
+
+    #[test]
+    fn settings_command_line_takes_precedence_test() {
+        let matches = cli::create_cli_app()
+            .get_matches_from(vec!["pg-dispatch", "--workers", "5"]);
+        let mut settings = Settings::from_matches(&matches);
+        settings.file = parse_config_file("workers = 8\nexec = cat").unwrap();
+
+        assert_eq!(settings.value_of("workers"), Some("5".to_string()));
+        assert_eq!(settings.value_of("exec"), Some("cat".to_string()));
+        assert!(settings.require("tls-mode").is_err());
+
+        settings.file = parse_config_file("route = a:* => ./a.sh\nroute = b:* => ./b.sh").unwrap();
+        assert_eq!(settings.values_of("route"), vec!["a:* => ./a.sh", "b:* => ./b.sh"]);
+
+        settings.file = parse_config_file("persistent = true").unwrap();
+        assert!(settings.flag("persistent"));
+        settings.file = parse_config_file("persistent = no").unwrap();
+        assert!(!settings.flag("persistent"));
+    }
+}
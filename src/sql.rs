@@ -0,0 +1,264 @@
+extern crate postgres;
+extern crate serde_json;
+
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::Instant;
+use self::postgres::types::{IsNull, ToSql, Type, BPCHAR, JSON, JSONB, NAME, TEXT, UNKNOWN, VARCHAR};
+use dispatcher;
+use executor::{Executor, Outcome, Output, Task};
+use log;
+
+/// SQLSTATE of a statement cancelled by `statement_timeout`.
+const QUERY_CANCELED: &str = "57014";
+
+/// Connections of a `SqlExecutor` not in use, and how many are open.
+#[derive(Debug, Default)]
+struct Pool {
+    idle: Vec<postgres::Connection>,
+    open: usize,
+}
+
+/// Runs a statement with the payload bound to `$1`, e.g.
+/// `SELECT process_event($1::jsonb)`, on a small pool of connections.
+#[derive(Debug, Clone)]
+pub struct SqlExecutor {
+    statement: String,
+    db_url: String,
+    tls_mode: String,
+    pool_size: usize,
+    /// Seconds a statement may run for, 0 for no limit.
+    timeout: u64,
+    /// SQLSTATE codes, or classes of them such as `08`, worth retrying.
+    retry_states: Vec<String>,
+    pool: Arc<(Mutex<Pool>, Condvar)>,
+}
+
+impl SqlExecutor {
+    pub fn new(
+        statement: String, db_url: String, tls_mode: String,
+        pool_size: usize, timeout: u64, retry_states: Vec<String>) -> SqlExecutor {
+        SqlExecutor {
+            statement, db_url, tls_mode,
+            pool_size: pool_size.max(1),
+            timeout,
+            retry_states,
+            pool: Arc::new((Mutex::new(Pool::default()), Condvar::new())),
+        }
+    }
+
+    pub fn retries(&self, sqlstate: &str) -> bool {
+        self.retry_states.iter().any(|state| sqlstate.starts_with(state.as_str()))
+    }
+
+    /// Takes an idle connection, or opens one when the pool is not full yet,
+    /// waiting for one to be given back otherwise.
+    fn checkout(&self) -> postgres::Result<postgres::Connection> {
+        let (ref lock, ref released) = *self.pool;
+        let mut pool = lock.lock().unwrap();
+        loop {
+            if let Some(conn) = pool.idle.pop() {
+                return Ok(conn);
+            }
+            if pool.open < self.pool_size {
+                pool.open += 1;
+                break;
+            }
+            pool = released.wait(pool).unwrap();
+        }
+        drop(pool);
+
+        let connected = dispatcher::connect(&self.db_url, &self.tls_mode).and_then(|conn| {
+            conn.batch_execute(&format!("SET statement_timeout = {}", self.timeout * 1000))?;
+            Ok(conn)
+        });
+        if connected.is_err() {
+            self.checkin(None);
+        }
+        connected
+    }
+
+    /// Gives a connection back, `None` for one that was closed.
+    fn checkin(&self, conn: Option<postgres::Connection>) {
+        let (ref lock, ref released) = *self.pool;
+        let mut pool = lock.lock().unwrap();
+        match conn {
+            Some(conn) => pool.idle.push(conn),
+            None => pool.open -= 1,
+        }
+        released.notify_one();
+    }
+}
+
+impl Executor for SqlExecutor {
+    /// Lets the statement run until it times out when aborted.
+    fn execute(&self, task: &Task, _abort: &AtomicBool) -> Outcome {
+        let started_at = Instant::now();
+        let conn = match self.checkout() {
+            Ok(conn) => conn,
+            Err(error) => {
+                log::error("couldn't connect to the database")
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("error", error.to_string())
+                    .emit();
+                return Outcome::Retryable(Output::default());
+            }
+        };
+
+        let payload = Payload(&task.payload);
+        let result = conn.prepare_cached(&self.statement).and_then(|statement| {
+            match statement.param_types().len() {
+                0 => statement.execute(&[]),
+                _ => statement.execute(&[&payload]),
+            }
+        });
+        // connections whose link broke are replaced
+        let broken = match result {
+            Err(ref error) => error.as_io().is_some() || conn.is_desynchronized(),
+            Ok(_) => false,
+        };
+        self.checkin(match broken {
+            true => None,
+            false => Some(conn),
+        });
+
+        let error = match result {
+            Ok(rows) => {
+                log::info("statement succeeded")
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("rows", rows)
+                    .field("duration", log::seconds(started_at.elapsed()))
+                    .emit();
+                return Outcome::Success(Output {
+                    code: Some("00000".to_string()),
+                    details: json!({ "sqlstate": "00000", "rows": rows }),
+                });
+            },
+            Err(error) => error,
+        };
+
+        let sqlstate = error.code().map(|state| state.code().to_string());
+        let unbound = error.as_conversion().is_some();
+        log::warn("statement failed")
+            .field("worker", task.worker)
+            .field("task", task.id())
+            .field("attempt", task.attempt)
+            .field("sqlstate", sqlstate.clone())
+            .field("error", error.as_db().map_or_else(|| error.to_string(), |db| db.message.clone()))
+            .field("duration", log::seconds(started_at.elapsed()))
+            .emit();
+        let output = Output {
+            details: json!({ "sqlstate": sqlstate }),
+            code: sqlstate,
+        };
+        match output.code.as_deref() {
+            Some(QUERY_CANCELED) => Outcome::Timeout(output),
+            Some(sqlstate) if self.retries(sqlstate) => Outcome::Retryable(output),
+            Some(_) => Outcome::Permanent(output),
+            // the payload could not be bound, e.g. as jsonb when not JSON
+            None if unbound => Outcome::Permanent(output),
+            // the connection broke
+            None => Outcome::Retryable(output),
+        }
+    }
+}
+
+impl PartialEq for SqlExecutor {
+    fn eq(&self, other: &SqlExecutor) -> bool {
+        self.statement == other.statement && self.db_url == other.db_url
+            && self.tls_mode == other.tls_mode && self.pool_size == other.pool_size
+            && self.timeout == other.timeout && self.retry_states == other.retry_states
+    }
+}
+
+/// A payload bound as text, or as JSON when the statement casts it to it
+/// and it is valid JSON.
+#[derive(Debug)]
+struct Payload<'a>(&'a str);
+
+impl<'a> ToSql for Payload<'a> {
+    fn to_sql(&self, ty: &Type, out: &mut Vec<u8>) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        if (*ty == JSON || *ty == JSONB) && serde_json::from_str::<serde_json::Value>(self.0).is_err() {
+            return Err(format!("the payload is not JSON, it cannot be bound as {}", ty).into());
+        }
+        // binary jsonb starts with its format version
+        if *ty == JSONB {
+            out.push(1);
+        }
+        out.extend_from_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        [TEXT, VARCHAR, BPCHAR, NAME, UNKNOWN, JSON, JSONB].contains(ty)
+    }
+
+    fn to_sql_checked(&self, ty: &Type, out: &mut Vec<u8>) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        if !Self::accepts(ty) {
+            return Err(format!("a payload cannot be bound as {}, cast it with e.g. $1::jsonb", ty).into());
+        }
+        self.to_sql(ty, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sql_retries_test() {
+        let executor = SqlExecutor::new(
+            "SELECT 1".to_string(), String::new(), "none".to_string(), 2, 30,
+            vec!["40001".to_string(), "08".to_string()]);
+
+        assert!(executor.retries("40001"));
+        assert!(executor.retries("08006"));
+        assert!(!executor.retries("23505"));
+    }
+
+    #[test]
+    fn payload_to_sql_test() {
+        let bound = |payload: &str, ty: &Type| {
+            let mut out = Vec::new();
+            Payload(payload).to_sql_checked(ty, &mut out).map(|_| out)
+        };
+
+        assert_eq!(bound("not json", &TEXT).unwrap(), b"not json".to_vec());
+        assert_eq!(bound(r#"{"id":1}"#, &JSON).unwrap(), br#"{"id":1}"#.to_vec());
+        assert_eq!(bound(r#"{"id":1}"#, &JSONB).unwrap(), b"\x01{\"id\":1}".to_vec());
+        assert!(bound("not json", &JSONB).is_err());
+        assert!(bound("not json", &JSON).is_err());
+        assert!(bound("1", &postgres::types::INT4).is_err());
+    }
+
+    #[test]
+    fn sql_pool_test() {
+        let executor = SqlExecutor::new(
+            "SELECT 1".to_string(), "postgres://nobody@127.0.0.1:1/foodb".to_string(), "none".to_string(),
+            1, 30, Vec::new());
+
+        // a connection that could not be opened frees its slot
+        assert!(executor.checkout().is_err());
+        assert_eq!(executor.pool.0.lock().unwrap().open, 0);
+        let task = Task { payload: "{}".to_string(), key: "e30=".to_string(), worker: 0, attempt: 1 };
+        assert_eq!(executor.execute(&task, &AtomicBool::new(false)), Outcome::Retryable(Output::default()));
+
+        // a full pool waits for a connection to be given back
+        executor.pool.0.lock().unwrap().open = 1;
+        let (sender, receiver) = mpsc::channel();
+        let waiting = executor.clone();
+        thread::spawn(move || sender.send(waiting.checkout().is_err()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        executor.checkin(None);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+        assert_eq!(executor.pool.0.lock().unwrap().open, 0);
+    }
+}
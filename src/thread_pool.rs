@@ -1,74 +1,239 @@
 extern crate base64;
 
-use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex, mpsc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::ffi::OsString;
-use std::io::{BufRead, BufReader, Write};
+use std::time::{Duration, Instant};
+use executor::{Executor, Outcome, Task};
+use log;
+use payload;
+use routes::Routes;
 
 /// For exchanging in the job channel
 enum Message {
-    Payload(String),
+    Payload(String, u64, Arc<dyn Executor>),
     Terminate,
 }
 
 pub enum WorkerMessage {
-    ProgramNotFound(String),
-    StdinFailed(String),
-    DoneTask(String),
+    Finished(String, Outcome, Duration),
+    Aborted(String),
 }
 
+impl WorkerMessage {
+    /// The base64 key of the task this message is about.
+    pub fn key(&self) -> &str {
+        match *self {
+            WorkerMessage::Finished(ref key, _, _)
+                | WorkerMessage::Aborted(ref key) => key
+        }
+    }
+}
+
+/// For reporting back to the pool
+enum PoolEvent {
+    Finished(usize, WorkerMessage),
+    Wakeup,
+}
+
+/// Interrupts a `ThreadPool::recv_timeout` from another thread.
+#[derive(Debug, Clone)]
+pub struct Waker {
+    sender: mpsc::Sender<PoolEvent>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        let _ = self.sender.send(PoolEvent::Wakeup);
+    }
+}
+
+/// Pool of workers, each fed through its own bounded job channel.
+///
+/// Jobs are only handed to workers known to be idle, and every finished job is
+/// reported back through `recv_timeout`/`try_recv`, which is what frees the
+/// worker slot again. Callers claim work according to `free_slots`.
 #[derive(Debug)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
-    pub workers_channel: mpsc::Receiver<WorkerMessage>,
-    pub idle_counter: Arc<Mutex<usize>>
+    workers: HashMap<usize, Worker>,
+    /// Idle workers with the time they became idle, most recently used last.
+    idle_workers: VecDeque<(usize, Instant)>,
+    next_worker_id: usize,
+    /// Busy workers to stop as soon as they finish, after shrinking the pool.
+    retiring: usize,
+    routes: Routes,
+    workers_sender: mpsc::Sender<PoolEvent>,
+    workers_channel: mpsc::Receiver<PoolEvent>,
+    abort: Arc<AtomicBool>,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize, command_vector: Vec<OsString>) -> ThreadPool {
+    pub fn new(size: usize, routes: Routes) -> ThreadPool {
         assert!(size > 0);
 
-        // channel for exchanging job messages inside ThreadPool
-        let (sender, receiver) = mpsc::channel();
+        // channel for workers to report finished jobs back to the pool
         let (workers_sender, workers_channel) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let idle_counter = Arc::new(Mutex::new(size));
 
-        let command_vector = Arc::new(command_vector);
+        let mut pool = ThreadPool {
+            workers: HashMap::with_capacity(size),
+            idle_workers: VecDeque::with_capacity(size),
+            next_worker_id: 0,
+            retiring: 0,
+            routes,
+            workers_sender,
+            workers_channel,
+            abort: Arc::new(AtomicBool::new(false)),
+        };
+        pool.resize(size);
+        pool
+    }
+
+    /// Number of workers the pool is sized for.
+    pub fn size(&self) -> usize {
+        self.workers.len() - self.retiring
+    }
+
+    /// Number of workers running a job.
+    pub fn busy_slots(&self) -> usize {
+        self.workers.len() - self.idle_workers.len()
+    }
+
+    /// Number of worker threads that exited unexpectedly, e.g. by panicking.
+    pub fn dead_workers(&self) -> usize {
+        self.workers.values()
+            .filter(|worker| worker.thread.as_ref().is_some_and(|thread| thread.is_finished()))
+            .count()
+    }
+
+    /// Number of workers ready to take a job right now.
+    pub fn free_slots(&self) -> usize {
+        self.idle_workers.len()
+    }
+
+    /// Executors used for the jobs executed from now on.
+    pub fn set_routes(&mut self, routes: Routes) {
+        self.routes = routes;
+    }
+
+    /// Grows or shrinks the pool. Idle workers are stopped right away, busy
+    /// ones once they finish their current job.
+    pub fn resize(&mut self, size: usize) {
+        assert!(size > 0);
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(
+        while self.size() < size {
+            if self.retiring > 0 {
+                self.retiring -= 1;
+                continue;
+            }
+            let id = self.next_worker_id;
+            self.next_worker_id += 1;
+            self.workers.insert(id, Worker::new(
                     id,
-                    idle_counter.clone(),
-                    workers_sender.clone(),
-                    receiver.clone(),
-                    Arc::clone(&command_vector),
+                    self.workers_sender.clone(),
+                    self.abort.clone(),
                     ));
+            self.idle_workers.push_back((id, Instant::now()));
+        }
+
+        while self.size() > size {
+            match self.idle_workers.pop_front() {
+                Some((id, _)) => self.stop_worker(id),
+                None => self.retiring += 1,
+            }
+        }
+    }
+
+    /// Stops workers that have been idle for longer than `idle_for`, keeping
+    /// at least `min_size` of them. Returns how many were stopped.
+    pub fn shrink_idle(&mut self, min_size: usize, idle_for: Duration) -> usize {
+        let mut stopped = 0;
+        while self.size() > min_size {
+            match self.idle_workers.front() {
+                Some(&(_, idle_since)) if idle_since.elapsed() >= idle_for => {},
+                _ => break,
+            }
+            let (id, _) = self.idle_workers.pop_front().unwrap();
+            self.stop_worker(id);
+            stopped += 1;
+        }
+        stopped
+    }
+
+    /// Hands the payload to an idle worker, running the executor routed to,
+    /// giving it back when there is none.
+    pub fn execute(&mut self, payload: String, attempt: u64) -> Result<(), String> {
+        // reuse the most recently used worker so the others can go idle long
+        // enough to be scaled down
+        match self.idle_workers.pop_back() {
+            Some((id, _)) => {
+                let executor = self.routes.executor_for(&payload);
+                self.workers[&id].sender.send(Message::Payload(payload, attempt, executor)).unwrap();
+                Ok(())
+            },
+            None => Err(payload)
+        }
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker { sender: self.workers_sender.clone() }
+    }
+
+    /// Returns a finished job without blocking, if any.
+    pub fn try_recv(&mut self) -> Option<WorkerMessage> {
+        loop {
+            match self.workers_channel.try_recv() {
+                Ok(PoolEvent::Finished(id, message)) => return Some(self.release(id, message)),
+                Ok(PoolEvent::Wakeup) => continue,
+                Err(_) => return None
+            }
+        }
+    }
+
+    /// Blocks until a job finishes, a `Waker` fires or the timeout elapses.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<WorkerMessage> {
+        match self.workers_channel.recv_timeout(timeout) {
+            Ok(PoolEvent::Finished(id, message)) => Some(self.release(id, message)),
+            Ok(PoolEvent::Wakeup) | Err(_) => None
         }
+    }
 
-        ThreadPool { workers, sender, workers_channel, idle_counter}
+    /// Stops every running job that can be, e.g. by killing its child; the
+    /// jobs that could be retried are reported as `Aborted`.
+    pub fn abort(&self) {
+        self.abort.store(true, Ordering::SeqCst);
     }
 
-    pub fn execute(&self, payload: String) {
-        self.sender.send(Message::Payload(payload)).unwrap();
+    fn release(&mut self, id: usize, message: WorkerMessage) -> WorkerMessage {
+        if self.retiring > 0 {
+            self.retiring -= 1;
+            self.stop_worker(id);
+        } else {
+            self.idle_workers.push_back((id, Instant::now()));
+        }
+        message
+    }
+
+    fn stop_worker(&mut self, id: usize) {
+        if let Some(mut worker) = self.workers.remove(&id) {
+            worker.sender.send(Message::Terminate).unwrap();
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        log::debug("sending terminate message to all workers").emit();
 
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        for worker in self.workers.values_mut() {
+            worker.sender.send(Message::Terminate).unwrap();
         }
 
-        println!("Shutting down all workers.");
-
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        for worker in self.workers.values_mut() {
+            log::debug("shutting down worker").field("worker", worker.id).emit();
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -80,101 +245,150 @@ impl Drop for ThreadPool {
 #[derive(Debug)]
 struct Worker {
     id: usize,
+    sender: mpsc::SyncSender<Message>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
     fn new(
         id: usize,
-        idle_counter: Arc<Mutex<usize>>,
-        workers_sender: mpsc::Sender<WorkerMessage>,
-        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
-        command_vector: Arc<Vec<OsString>>,
+        workers_sender: mpsc::Sender<PoolEvent>,
+        abort: Arc<AtomicBool>,
         ) -> Worker {
 
+        // a worker holds at most one job: the pool only sends to idle workers
+        let (sender, receiver) = mpsc::sync_channel(1);
+
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            let program = &command_vector[0];
-            let program_arguments = &command_vector[1..];
+            let message = receiver.recv().unwrap();
 
             match message {
-                Message::Payload(payload) => {
-                    {
-                        let guard_idle_counter = idle_counter.clone();
-                        *guard_idle_counter.lock().unwrap() -= 1;
-                    }
-                    let payload_base64 = base64::encode(&payload);
-                    println!("[worker-{}] Got payload: {}.", id, payload);
-
-                    // spawn child command
-                    // TODO: if a thread panics, does the threadpool replaces them?
-                    let child_command =
-                        Command::new(&program)
-                        .args(program_arguments)
-                        .stdin(Stdio::piped())
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .spawn();
-
-                    if let Ok(mut child) = child_command {
-                        // pass payload data through child process stdin
-                        let write_to_child = child
-                            .stdin
-                            .take()
-                            .unwrap()
-                            .write_all(payload.as_bytes());
-                        if let Ok(_) = write_to_child {
-                            let exit_status = child.wait().unwrap();
-                            match exit_status.success() {
-                                true => {
-                                    println!(
-                                        "[worker-{}] Command succeded with status code {}.",
-                                        id, exit_status.code().unwrap());
-                                }
-                                false => {
-                                    // TODO: ExitStatus.code() will return None if process was terminated by a signal.
-                                    eprintln!(
-                                        "[worker-{}] Command {} failed with status code {}.",
-                                        id, program.to_str().unwrap(), exit_status.code().unwrap());
-                                }
-                            }
-                            // propagate standard streams
-                            for line in BufReader::new(child.stderr.take().unwrap()).lines() {
-                                eprintln!("[{}-{}]! {}", program.to_str().unwrap(), id, line.unwrap())
-                            }
-                            for line in BufReader::new(child.stdout.take().unwrap()).lines() {
-                                println!("[{}-{}] {}", program.to_str().unwrap(), id, line.unwrap());
-                            }
-                            workers_sender
-                                .send(WorkerMessage::DoneTask(payload_base64))
-                                .unwrap();
-                        } else {
-                            workers_sender
-                                .send(WorkerMessage::StdinFailed(payload_base64))
-                                .unwrap();
-                            eprintln!("couldn't write to child process stdin");
-                        }
-                    } else {
+                Message::Payload(payload, attempt, executor) => {
+                    let task = Task { key: base64::encode(&payload), payload, worker: id, attempt };
+                    if abort.load(Ordering::SeqCst) {
                         workers_sender
-                            .send(WorkerMessage::ProgramNotFound(payload_base64))
+                            .send(PoolEvent::Finished(id, WorkerMessage::Aborted(task.key)))
                             .unwrap();
-                        eprintln!("couldn't execute program {:?}", program);
+                        continue;
                     }
+                    log::debug("got task")
+                        .field("worker", id)
+                        .field("task", payload::task_id(&task.key))
+                        .field("attempt", attempt)
+                        .emit();
 
-                {
-                    let guard_idle_counter = idle_counter.clone();
-                    *guard_idle_counter.lock().unwrap() += 1;
+                    let started_at = Instant::now();
+                    let outcome = executor.execute(&task, &abort);
+                    let message = match outcome {
+                        // handed back right away rather than retried later
+                        Outcome::Retryable(_) | Outcome::Timeout(_) if abort.load(Ordering::SeqCst) =>
+                            WorkerMessage::Aborted(task.key),
+                        outcome => WorkerMessage::Finished(task.key, outcome, started_at.elapsed()),
+                    };
+                    workers_sender.send(PoolEvent::Finished(id, message)).unwrap();
                 }
-            }
             Message::Terminate => {
-                println!("[worker-{}] Terminating.", id);
+                log::debug("worker terminating").field("worker", id).emit();
                 break;
             }
         }
     });
     Worker {
         id,
+        sender,
         thread: Some(thread),
     }
 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use executor::Output;
+
+    #[test]
+    fn thread_pool_frees_slot_on_completion_test() {
+        let mut pool = ThreadPool::new(1, vec![OsString::from("cat")].into());
+
+        assert_eq!(pool.free_slots(), 1);
+        assert!(pool.execute("foo".to_string(), 1).is_ok());
+        assert_eq!(pool.free_slots(), 0);
+        assert_eq!(pool.execute("bar".to_string(), 1), Err("bar".to_string()));
+
+        match pool.recv_timeout(Duration::from_secs(5)) {
+            Some(WorkerMessage::Finished(key, Outcome::Success(output), _)) => {
+                assert_eq!(key, base64::encode("foo"));
+                assert_eq!(output.code.as_deref(), Some("0"));
+            },
+            _ => panic!("expected the task to be done"),
+        }
+        assert_eq!(pool.free_slots(), 1);
+    }
+
+    #[test]
+    fn thread_pool_resize_test() {
+        let mut pool = ThreadPool::new(2, vec![OsString::from("cat")].into());
+
+        pool.resize(4);
+        assert_eq!(pool.size(), 4);
+        assert_eq!(pool.free_slots(), 4);
+
+        assert!(pool.execute("foo".to_string(), 1).is_ok());
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.free_slots(), 0);
+
+        assert!(pool.recv_timeout(Duration::from_secs(5)).is_some());
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.free_slots(), 1);
+    }
+
+    #[test]
+    fn thread_pool_shrink_idle_test() {
+        let mut pool = ThreadPool::new(3, vec![OsString::from("cat")].into());
+
+        assert_eq!(pool.shrink_idle(1, Duration::from_secs(60)), 0);
+        assert_eq!(pool.shrink_idle(1, Duration::from_secs(0)), 2);
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.free_slots(), 1);
+    }
+
+    #[test]
+    fn thread_pool_abort_kills_running_child_test() {
+        let mut pool = ThreadPool::new(
+            1, vec![OsString::from("sleep"), OsString::from("10")].into());
+
+        assert!(pool.execute("foo".to_string(), 1).is_ok());
+        pool.abort();
+
+        match pool.recv_timeout(Duration::from_secs(5)) {
+            Some(WorkerMessage::Aborted(key)) => assert_eq!(key, base64::encode("foo")),
+            _ => panic!("expected the task to be aborted"),
+        }
+    }
+
+    #[derive(Debug)]
+    struct Reversed;
+
+    impl Executor for Reversed {
+        fn execute(&self, task: &Task, _abort: &AtomicBool) -> Outcome {
+            Outcome::Permanent(Output {
+                code: None,
+                details: json!({ "reversed": task.payload.chars().rev().collect::<String>() }),
+            })
+        }
+    }
+
+    #[test]
+    fn thread_pool_custom_executor_test() {
+        let mut pool = ThreadPool::new(1, Routes::new(Vec::new(), Arc::new(Reversed)));
+
+        assert!(pool.execute("foo".to_string(), 1).is_ok());
+        match pool.recv_timeout(Duration::from_secs(5)) {
+            Some(WorkerMessage::Finished(_, Outcome::Permanent(output), _)) =>
+                assert_eq!(output.details, json!({ "reversed": "oof" })),
+            _ => panic!("expected the task to fail"),
+        }
+    }
+}
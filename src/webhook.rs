@@ -0,0 +1,187 @@
+extern crate hmac;
+extern crate sha2;
+extern crate ureq;
+
+use std::error::Error;
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+use executor::{Executor, Outcome, Output, Task};
+use log;
+
+/// Header holding the HMAC-SHA256 of the body when a secret is set.
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+/// Most bytes of a response body kept for the logs.
+const MAX_BODY: u64 = 1024;
+
+/// What to do with a task after its webhook responded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Succeeded,
+    /// The endpoint is busy or down, the task is tried again later.
+    Retry,
+    Failed,
+}
+
+impl Verdict {
+    pub fn of_status(status: u16) -> Verdict {
+        match status {
+            200..=299 => Verdict::Succeeded,
+            408 | 429 | 500..=599 => Verdict::Retry,
+            _ => Verdict::Failed,
+        }
+    }
+}
+
+/// Endpoint the payloads are POSTed to, instead of running a command.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    secret: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl Webhook {
+    pub fn new(url: String, headers: Vec<(String, String)>, timeout: Duration, secret: Option<String>) -> Webhook {
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        Webhook { url, headers, timeout, secret, agent }
+    }
+
+    /// Parses a `Name: value` header.
+    pub fn parse_header(header: &str) -> Result<(String, String), String> {
+        match header.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+            _ => Err(format!("invalid header {}, expected Name: value", header)),
+        }
+    }
+
+    /// POSTs the payload, returning the response status and the start of its
+    /// body, or why no response came back.
+    pub fn post(&self, payload: &str) -> Result<(u16, String), Box<ureq::Transport>> {
+        let mut request = self.agent.post(&self.url).set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        if let Some(signature) = self.signature(payload) {
+            request = request.set(SIGNATURE_HEADER, &signature);
+        }
+
+        let response = match request.send_string(payload) {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(ureq::Error::Transport(error)) => return Err(Box::new(error)),
+        };
+        let status = response.status();
+        let mut body = String::new();
+        let _ = response.into_reader().take(MAX_BODY).read_to_string(&mut body);
+        Ok((status, body))
+    }
+
+    /// `sha256=<hex digest>` of the payload keyed with the secret.
+    pub fn signature(&self, payload: &str) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(payload.as_bytes());
+        let mut signature = String::from("sha256=");
+        for byte in mac.finalize().into_bytes() {
+            let _ = write!(signature, "{:02x}", byte);
+        }
+        Some(signature)
+    }
+}
+
+impl Executor for Webhook {
+    /// Lets the request run until it times out when aborted.
+    fn execute(&self, task: &Task, _abort: &AtomicBool) -> Outcome {
+        let started_at = Instant::now();
+        let (status, body) = match self.post(&task.payload) {
+            Ok(response) => response,
+            Err(error) => {
+                let timed_out = timed_out(&error);
+                match timed_out {
+                    true => log::warn("webhook timed out, retrying later"),
+                    false => log::warn("webhook unreachable, retrying later"),
+                }
+                    .field("worker", task.worker)
+                    .field("task", task.id())
+                    .field("attempt", task.attempt)
+                    .field("error", error.to_string())
+                    .emit();
+                return match timed_out {
+                    true => Outcome::Timeout(Output::default()),
+                    false => Outcome::Retryable(Output::default()),
+                };
+            }
+        };
+
+        let verdict = Verdict::of_status(status);
+        let mut event = match verdict {
+            Verdict::Succeeded => log::info("webhook succeeded"),
+            Verdict::Retry => log::warn("webhook failed, retrying later"),
+            Verdict::Failed => log::warn("webhook failed"),
+        }
+            .field("worker", task.worker)
+            .field("task", task.id())
+            .field("attempt", task.attempt)
+            .field("status", status)
+            .field("duration", log::seconds(started_at.elapsed()));
+        if verdict != Verdict::Succeeded && !body.is_empty() {
+            event = event.field("response", body);
+        }
+        event.emit();
+
+        let output = Output { code: Some(status.to_string()), details: json!({ "status": status }) };
+        match verdict {
+            Verdict::Succeeded => Outcome::Success(output),
+            Verdict::Retry => Outcome::Retryable(output),
+            Verdict::Failed => Outcome::Permanent(output),
+        }
+    }
+}
+
+/// Whether no response came back within the timeout.
+fn timed_out(error: &ureq::Transport) -> bool {
+    error.source()
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .is_some_and(|error| error.kind() == io::ErrorKind::TimedOut || error.kind() == io::ErrorKind::WouldBlock)
+}
+
+impl PartialEq for Webhook {
+    fn eq(&self, other: &Webhook) -> bool {
+        self.url == other.url && self.headers == other.headers
+            && self.timeout == other.timeout && self.secret == other.secret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_verdict_test() {
+        assert_eq!(Verdict::of_status(204), Verdict::Succeeded);
+        assert_eq!(Verdict::of_status(429), Verdict::Retry);
+        assert_eq!(Verdict::of_status(503), Verdict::Retry);
+        assert_eq!(Verdict::of_status(404), Verdict::Failed);
+        assert_eq!(Webhook::parse_header("Authorization: Bearer a:b"),
+                   Ok(("Authorization".to_string(), "Bearer a:b".to_string())));
+        assert!(Webhook::parse_header("Authorization").is_err());
+    }
+
+    #[test]
+    fn webhook_signature_test() {
+        let url = "http://localhost/hook".to_string();
+        let timeout = Duration::from_secs(30);
+        let webhook = Webhook::new(url.clone(), vec![], timeout, Some("key".to_string()));
+
+        assert_eq!(
+            webhook.signature("The quick brown fox jumps over the lazy dog"),
+            Some("sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8".to_string()));
+        assert_eq!(Webhook::new(url, vec![], timeout, None).signature("{}"), None);
+    }
+}